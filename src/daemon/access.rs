@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use anyhow::*;
+use ipnet::IpNet;
+use serde_derive::{Deserialize, Serialize};
+
+// forget idle clients, then the longest unseen, once we're tracking this many, rather than growing forever
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccessConfig {
+    #[serde(default)]
+    pub(crate) allow: Vec<IpNet>, // empty allows everyone
+    #[serde(default)]
+    pub(crate) deny: Vec<IpNet>, // checked before `allow`
+    pub(crate) rate_limit: Option<RateLimitConfig>,
+}
+
+/// A token bucket per client IP, refilled at `per_second` up to `burst` connections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    pub(crate) burst: u32,
+    pub(crate) per_second: f64,
+}
+
+impl AccessConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(limit) = &self.rate_limit {
+            if limit.burst == 0 {
+                bail!("`access.rate_limit.burst` has to be at least 1, or no connection is ever admitted");
+            }
+            if !(limit.per_second.is_finite() && limit.per_second > 0.0) {
+                bail!("`access.rate_limit.per_second` has to be more than 0, or clients are never let back in");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Verdict {
+    Admit,
+    Denied,
+    RateLimited,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub(crate) struct AccessPolicy {
+    config: AccessConfig,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl AccessPolicy {
+    pub(crate) fn new(config: AccessConfig) -> Self {
+        AccessPolicy { config, buckets: HashMap::new() }
+    }
+
+    /// Swaps in a new config, keeping rate limit state if the limits didn't change.
+    pub(crate) fn reconfigure(&mut self, config: AccessConfig) {
+        if config.rate_limit != self.config.rate_limit {
+            self.buckets.clear();
+        }
+        self.config = config;
+    }
+
+    pub(crate) fn check(&mut self, ip: IpAddr) -> Verdict {
+        if self.config.deny.iter().any(|net| net.contains(&ip)) {
+            return Verdict::Denied;
+        }
+
+        if !self.config.allow.is_empty() && !self.config.allow.iter().any(|net| net.contains(&ip)) {
+            return Verdict::Denied;
+        }
+
+        let limit = match &self.config.rate_limit {
+            Some(limit) => limit.clone(),
+            None => return Verdict::Admit,
+        };
+
+        let now = Instant::now();
+        if self.buckets.len() >= MAX_TRACKED_CLIENTS && !self.buckets.contains_key(&ip) {
+            self.make_room(&limit, now);
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * limit.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(limit.burst as f64);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Verdict::Admit
+        } else {
+            Verdict::RateLimited
+        }
+    }
+
+    /// Forgets clients whose buckets have refilled, which is no different from forgetting them at all,
+    /// or if every client is busy, the one seen longest ago. Clearing everything would let anyone
+    /// with enough addresses reset everyone's limits.
+    fn make_room(&mut self, limit: &RateLimitConfig, now: Instant) {
+        let full = |bucket: &Bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * limit.per_second >= limit.burst as f64
+        };
+        self.buckets.retain(|_, bucket| !full(bucket));
+
+        if self.buckets.len() >= MAX_TRACKED_CLIENTS {
+            let oldest = self.buckets.iter().min_by_key(|(_, bucket)| bucket.updated).map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.buckets.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str], rate_limit: Option<(u32, f64)>) -> AccessPolicy {
+        AccessPolicy::new(AccessConfig {
+            allow: allow.iter().map(|net| net.parse().unwrap()).collect(),
+            deny: deny.iter().map(|net| net.parse().unwrap()).collect(),
+            rate_limit: rate_limit.map(|(burst, per_second)| RateLimitConfig { burst, per_second }),
+        })
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_is_checked_before_allow() {
+        let mut policy = policy(&["10.0.0.0/8"], &["10.0.0.5/32"], None);
+        assert_eq!(policy.check(ip("10.0.0.1")), Verdict::Admit);
+        assert_eq!(policy.check(ip("10.0.0.5")), Verdict::Denied);
+        assert_eq!(policy.check(ip("192.168.0.1")), Verdict::Denied);
+    }
+
+    #[test]
+    fn empty_allow_admits_everyone() {
+        let mut policy = policy(&[], &[], None);
+        assert_eq!(policy.check(ip("::1")), Verdict::Admit);
+    }
+
+    #[test]
+    fn burst_then_rate_limited() {
+        let mut policy = policy(&[], &[], Some((3, 0.001)));
+        for _ in 0..3 {
+            assert_eq!(policy.check(ip("10.0.0.1")), Verdict::Admit);
+        }
+        assert_eq!(policy.check(ip("10.0.0.1")), Verdict::RateLimited);
+        // other clients have their own bucket
+        assert_eq!(policy.check(ip("10.0.0.2")), Verdict::Admit);
+    }
+
+    #[test]
+    fn new_clients_dont_reset_limited_ones() {
+        let mut policy = policy(&[], &[], Some((1, 0.001)));
+        let attacker = |i: u32| IpAddr::from((0x2001_0db8_u128 << 96 | i as u128).to_be_bytes());
+
+        for i in 0..MAX_TRACKED_CLIENTS as u32 - 1 {
+            policy.check(attacker(i));
+        }
+        assert_eq!(policy.check(ip("10.0.0.1")), Verdict::Admit);
+        assert_eq!(policy.check(ip("10.0.0.1")), Verdict::RateLimited);
+
+        // each new address only pushes out the one seen longest ago
+        for i in MAX_TRACKED_CLIENTS as u32..MAX_TRACKED_CLIENTS as u32 + 100 {
+            assert_eq!(policy.check(attacker(i)), Verdict::Admit);
+        }
+        assert_eq!(policy.buckets.len(), MAX_TRACKED_CLIENTS);
+        assert_eq!(policy.check(ip("10.0.0.1")), Verdict::RateLimited);
+        assert!(!policy.buckets.contains_key(&attacker(0)));
+    }
+
+    #[test]
+    fn reconfiguring_limits_forgets_buckets() {
+        let mut policy = policy(&[], &[], Some((1, 0.001)));
+        policy.check(ip("10.0.0.1"));
+        assert_eq!(policy.check(ip("10.0.0.1")), Verdict::RateLimited);
+
+        policy.reconfigure(AccessConfig { rate_limit: Some(RateLimitConfig { burst: 2, per_second: 0.001 }), ..Default::default() });
+        assert_eq!(policy.check(ip("10.0.0.1")), Verdict::Admit);
+    }
+
+    #[test]
+    fn limits_have_to_admit_someone() {
+        assert!(policy(&[], &[], Some((0, 1.0))).config.validate().is_err());
+        assert!(policy(&[], &[], Some((1, 0.0))).config.validate().is_err());
+        assert!(policy(&[], &[], Some((1, f64::NAN))).config.validate().is_err());
+        assert!(policy(&[], &[], Some((1, 0.5))).config.validate().is_ok());
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::*;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    pub(crate) path: String,
+    #[serde(default = "default_max_bytes")]
+    pub(crate) max_bytes: u64, // rotate once the log grows past this
+    #[serde(default = "default_keep")]
+    pub(crate) keep: usize, // number of rotated logs to keep around
+}
+
+fn default_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CloseReason {
+    ClientClosed,
+    ClientError,
+    BackendClosed,
+    BackendError,
+    Timeout,
+    Denied,
+    RateLimited,
+    Evicted,
+}
+
+impl CloseReason {
+    /// Whether the backend is to blame for the connection closing.
+    pub(crate) fn is_backend_failure(&self) -> bool {
+        matches!(self, CloseReason::BackendError | CloseReason::Timeout)
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::ClientClosed => write!(f, "client-closed"),
+            CloseReason::ClientError => write!(f, "client-error"),
+            CloseReason::BackendClosed => write!(f, "backend-closed"),
+            CloseReason::BackendError => write!(f, "backend-error"),
+            CloseReason::Timeout => write!(f, "timeout"),
+            CloseReason::Denied => write!(f, "denied"),
+            CloseReason::RateLimited => write!(f, "rate-limited"),
+            CloseReason::Evicted => write!(f, "evicted"),
+        }
+    }
+}
+
+/// Everything we know about a proxied connection once it has closed.
+#[derive(Debug)]
+pub(crate) struct ConnectionRecord {
+    pub(crate) client: SocketAddr,
+    pub(crate) color: String,
+    pub(crate) port: u16,
+    pub(crate) duration: Duration,
+    pub(crate) bytes_in: u64, // client to backend
+    pub(crate) bytes_out: u64, // backend to client
+    pub(crate) reason: CloseReason,
+}
+
+pub(crate) struct AccessLog {
+    config: AccessLogConfig,
+    file: File,
+    size: u64,
+}
+
+impl AccessLog {
+    pub(crate) fn open(config: AccessLogConfig) -> Result<AccessLog> {
+        if let Some(parent) = Path::new(&config.path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)
+            .with_context(|| format!("could not open access log {}", config.path))?;
+        let size = file.metadata()?.len();
+
+        Ok(AccessLog { config, file, size })
+    }
+
+    pub(crate) fn record(&mut self, record: &ConnectionRecord) -> Result<()> {
+        let line = format!(
+            "{} client={} backend={}:{} duration={:.3}s in={} out={} close={}\n",
+            chrono::Local::now().to_rfc3339(),
+            record.client,
+            record.color,
+            record.port,
+            record.duration.as_secs_f64(),
+            record.bytes_in,
+            record.bytes_out,
+            record.reason,
+        );
+
+        if self.size + line.len() as u64 > self.config.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `log.1` to `log.2` and so on, dropping the oldest, then starts a fresh log.
+    fn rotate(&mut self) -> Result<()> {
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            for i in (1..self.config.keep).rev() {
+                let _ = std::fs::rename(format!("{}.{}", path, i), format!("{}.{}", path, i + 1));
+            }
+            std::fs::rename(path, format!("{}.1", path))?;
+        }
+
+        *self = AccessLog::open(self.config.clone())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(reason: CloseReason) -> ConnectionRecord {
+        ConnectionRecord {
+            client: "10.0.0.7:51234".parse().unwrap(),
+            color: "blue".to_string(),
+            port: 9002,
+            duration: Duration::from_millis(1500),
+            bytes_in: 120,
+            bytes_out: 4096,
+            reason,
+        }
+    }
+
+    fn config(test: &str, max_bytes: u64, keep: usize) -> AccessLogConfig {
+        let dir = std::env::temp_dir().join(format!("dorc-access-log-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        AccessLogConfig { path: dir.join("access.log").display().to_string(), max_bytes, keep }
+    }
+
+    #[test]
+    fn records_one_line_per_connection() {
+        let config = config("format", default_max_bytes(), default_keep());
+        let mut log = AccessLog::open(config.clone()).unwrap();
+        log.record(&record(CloseReason::BackendError)).unwrap();
+        log.record(&record(CloseReason::RateLimited)).unwrap();
+
+        let written = std::fs::read_to_string(&config.path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+
+        let (time, fields) = lines[0].split_once(' ').unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(time).is_ok(), "{} isn't a timestamp", time);
+        assert_eq!(fields, "client=10.0.0.7:51234 backend=blue:9002 duration=1.500s in=120 out=4096 close=backend-error");
+        assert!(lines[1].ends_with(" close=rate-limited"));
+
+        std::fs::remove_dir_all(Path::new(&config.path).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rotates_past_max_bytes_and_keeps_only_so_many() {
+        // every record is longer than this, so each one rotates the log
+        let config = config("rotate", 64, 2);
+        let mut log = AccessLog::open(config.clone()).unwrap();
+        for _ in 0..4 {
+            log.record(&record(CloseReason::ClientClosed)).unwrap();
+        }
+
+        let lines = |path: &str| std::fs::read_to_string(path).map(|s| s.lines().count()).unwrap_or(0);
+        assert_eq!(lines(&config.path), 1);
+        assert_eq!(lines(&format!("{}.1", config.path)), 1);
+        assert_eq!(lines(&format!("{}.2", config.path)), 1);
+        assert!(!Path::new(&format!("{}.3", config.path)).exists());
+
+        std::fs::remove_dir_all(Path::new(&config.path).parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+
+use log::*;
+
+/// Listening sockets handed to the daemon by systemd, keyed by their `FileDescriptorName`.
+/// See sd_listen_fds(3).
+pub(crate) fn inherited_listeners() -> HashMap<String, TcpListener> {
+    let fds = match sd_notify::listen_fds_with_names(true) {
+        Ok(fds) => fds,
+        Err(e) => {
+            error!("Could not read sockets passed in by systemd: {}", e);
+            return HashMap::new();
+        }
+    };
+
+    fds.map(|(fd, name)| {
+        info!("Inherited listener '{}' from systemd (fd {})", name, fd);
+        // systemd hands over ownership of every fd from LISTEN_FDS
+        (name, unsafe { TcpListener::from_raw_fd(fd) })
+    })
+    .collect()
+}
@@ -0,0 +1,99 @@
+use std::io::{BufRead, Write};
+use std::process::Command;
+use std::sync::mpsc::Sender;
+
+use anyhow::{bail, Context};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::*;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+use crate::daemon::{parse_command, Commands, Reply};
+use crate::settings::settings;
+
+/// What the admin listener is handed off as. Not a valid app name, so it can't be taken for one.
+pub(crate) const HANDOFF_NAME: &str = "@admin";
+
+/// Takes FIFO commands over TCP, one per line, answering each with `ok` or `error: <why>` once it's been run.
+/// Anyone who can connect can run them, so `admin_address` should only be reachable by admins.
+/// Connections are served by this task rather than spawned, so aborting it closes them too.
+pub(crate) async fn serve(listener: std::net::TcpListener, sender: Sender<(Commands, Reply)>) {
+    let listener = match listener.set_nonblocking(true).and_then(|_| TcpListener::from_std(listener)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not listen for admin commands: {}", e);
+            return;
+        }
+    };
+
+    let mut connections = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    debug!("Admin connection from {}", peer);
+                    connections.push(handle(stream, sender.clone()));
+                }
+                Err(e) => error!("Failed to accept admin connection: {}", e),
+            },
+            Some(_) = connections.next(), if !connections.is_empty() => {}
+        }
+    }
+}
+
+async fn handle(stream: TcpStream, sender: Sender<(Commands, Reply)>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = match parse_command(&line) {
+            Ok(Some(command)) => {
+                let (reply, result) = oneshot::channel();
+                match sender.send((command, Reply::to(reply))) {
+                    Ok(_) => match result.await {
+                        Ok(Ok(())) => "ok".to_string(),
+                        Ok(Err(e)) => format!("error: {}", e.replace('\n', " ")),
+                        Err(_) => "error: the daemon was handed off before running it".to_string(),
+                    },
+                    Err(_) => "error: the daemon is shutting down".to_string(),
+                }
+            }
+            Ok(None) => continue,
+            Err(e) => format!("error: {}", e),
+        };
+
+        if write.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends `command` to the daemon, over `admin_address` if it's set, failing if the daemon says it did.
+/// The FIFO can't answer, so without an admin address, failures only show up in the daemon's log.
+pub(crate) fn send(command: &str) -> anyhow::Result<()> {
+    let address = match settings().admin_address {
+        Some(address) => address,
+        None => {
+            // TODO: spawn an async thread and kill after 3 seconds.
+            Command::new("sh")
+                .arg("-c")
+                .arg(format!("echo '{}' > {}", command, settings().fifo))
+                .output()
+                .context("failed to write to the daemon's FIFO")?;
+            return Ok(());
+        }
+    };
+
+    let mut stream = std::net::TcpStream::connect(address)
+        .with_context(|| format!("could not reach the daemon on {}", address))?;
+    writeln!(stream, "{}", command)?;
+
+    let mut answer = String::new();
+    std::io::BufReader::new(stream).read_line(&mut answer)?;
+    match answer.trim() {
+        "ok" => Ok(()),
+        "" => bail!("the daemon closed the connection without answering"),
+        answer => bail!("{}", answer.strip_prefix("error: ").unwrap_or(answer)),
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::daemon::proxy::Backend;
+use crate::registration::types::Service;
+use crate::settings::settings;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+/// How failing instances are noticed and left out, where an app differs from the `[defaults]` in dorc.toml.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HealthConfig {
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub(crate) connect_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub(crate) down_for: Option<Duration>,
+}
+
+impl HealthConfig {
+    pub(crate) fn connect_timeout(&self) -> Duration {
+        self.connect_timeout.unwrap_or(settings().defaults.connect_timeout)
+    }
+
+    pub(crate) fn down_for(&self) -> Duration {
+        self.down_for.unwrap_or(settings().defaults.down_for)
+    }
+}
+
+struct Member {
+    port: u16,
+    connections: AtomicUsize,
+    down_until: std::sync::Mutex<Option<Instant>>,
+}
+
+impl Member {
+    fn is_up(&self) -> bool {
+        match *self.down_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+}
+
+/// A color's instances, which new connections are spread across.
+pub(crate) struct Pool {
+    pub(crate) color: String,
+    strategy: Strategy,
+    health: HealthConfig,
+    members: Vec<Arc<Member>>,
+    next: AtomicUsize,
+}
+
+/// An instance picked for a connection, counted against it until dropped.
+pub(crate) struct Lease {
+    pub(crate) backend: Backend,
+    member: Arc<Member>,
+    down_for: Duration,
+}
+
+impl Lease {
+    /// Takes the instance out of rotation for a while.
+    pub(crate) fn mark_down(&self) {
+        *self.member.down_until.lock().unwrap() = Some(Instant::now() + self.down_for);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.member.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Pool {
+    pub(crate) fn new(service: &Service, strategy: Strategy, health: &HealthConfig) -> Self {
+        Pool {
+            color: service.color().to_string(),
+            strategy,
+            health: health.clone(),
+            members: service.instances().iter().map(|instance| Arc::new(Member {
+                port: instance.port,
+                connections: AtomicUsize::new(0),
+                down_until: Default::default(),
+            })).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn ports(&self) -> Vec<u16> {
+        self.members.iter().map(|m| m.port).collect()
+    }
+
+    /// Whether `other` routes to the same instances the same way, so this pool's state can be kept.
+    pub(crate) fn same_as(&self, other: &Pool) -> bool {
+        self.color == other.color && self.strategy == other.strategy && self.health == other.health && self.ports() == other.ports()
+    }
+
+    /// How long connecting to an instance may take before it counts as down.
+    pub(crate) fn connect_timeout(&self) -> Duration {
+        self.health.connect_timeout()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Picks an instance that's up, or any instance if none are.
+    pub(crate) fn pick(&self) -> Lease {
+        let up: Vec<&Arc<Member>> = self.members.iter().filter(|m| m.is_up()).collect();
+        let candidates = if up.is_empty() { self.members.iter().collect() } else { up };
+
+        let member = match self.strategy {
+            Strategy::RoundRobin => candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()],
+            Strategy::LeastConnections => candidates.iter()
+                .min_by_key(|m| m.connections.load(Ordering::SeqCst))
+                .unwrap(),
+        };
+
+        member.connections.fetch_add(1, Ordering::SeqCst);
+        Lease {
+            backend: Backend { port: member.port },
+            member: member.clone(),
+            down_for: self.health.down_for(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(port: u16, instances: u16) -> Service {
+        toml::from_str(&format!(
+            "qualified_name = \"blue-app\"\nworking_dir = \"/tmp/blue-app\"\nport = {}\ninstances = {}\non_start = \"app -p {{port}}\"",
+            port, instances
+        )).unwrap()
+    }
+
+    fn picks(pool: &Pool, count: usize) -> Vec<u16> {
+        (0..count).map(|_| pool.pick().backend.port).collect()
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let pool = Pool::new(&slot(9000, 3), Strategy::RoundRobin, &HealthConfig::default());
+        assert_eq!(pool.ports(), vec![9000, 9001, 9002]);
+        assert_eq!(picks(&pool, 4), vec![9000, 9001, 9002, 9000]);
+    }
+
+    #[test]
+    fn least_connections_avoids_busy_instances() {
+        let pool = Pool::new(&slot(9000, 2), Strategy::LeastConnections, &HealthConfig::default());
+        let busy = pool.pick();
+        assert_eq!(busy.backend.port, 9000);
+        assert_eq!(picks(&pool, 2), vec![9001, 9001]);
+
+        drop(busy);
+        assert_eq!(pool.pick().backend.port, 9000);
+    }
+
+    #[test]
+    fn skips_instances_that_are_down() {
+        let pool = Pool::new(&slot(9000, 2), Strategy::RoundRobin, &HealthConfig::default());
+        pool.pick().mark_down();
+        assert_eq!(picks(&pool, 3), vec![9001, 9001, 9001]);
+
+        // with nothing up, anything beats refusing the connection
+        pool.pick().mark_down();
+        let mut both = picks(&pool, 2);
+        both.sort();
+        assert_eq!(both, vec![9000, 9001]);
+    }
+
+    #[test]
+    fn instances_stop_at_the_last_port() {
+        assert_eq!(slot(65534, 2).last_port(), Some(65535));
+        assert_eq!(slot(65535, 2).last_port(), None);
+        assert_eq!(Pool::new(&slot(65535, 2), Strategy::RoundRobin, &HealthConfig::default()).ports(), vec![65535]);
+    }
+
+    #[test]
+    fn apps_can_shorten_how_long_instances_are_down() {
+        let health = HealthConfig { down_for: Some(Duration::ZERO), ..Default::default() };
+        let pool = Pool::new(&slot(9000, 2), Strategy::RoundRobin, &health);
+        pool.pick().mark_down();
+        assert_eq!(picks(&pool, 2), vec![9001, 9000]);
+        assert!(!pool.same_as(&Pool::new(&slot(9000, 2), Strategy::RoundRobin, &HealthConfig::default())));
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::Sender;
+
+use anyhow::*;
+use log::*;
+use sendfd::{RecvWithFd, SendWithFd};
+
+use crate::daemon::{Commands, Reply};
+use crate::settings::settings;
+
+// more than enough for one listener per app
+const MAX_FDS: usize = 253;
+
+/// Waits for a new daemon to ask for our listeners.
+/// Each connection is passed on to the daemon, which owns the proxies.
+pub(crate) async fn serve(sender: Sender<(Commands, Reply)>) {
+    let socket = &settings().handoff_socket;
+    let _ = crate::settings::create_parent(socket);
+    let _ = std::fs::remove_file(socket);
+    let listener = match tokio::net::UnixListener::bind(socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not bind {}, zero-downtime upgrades are unavailable: {}", socket, e);
+            return;
+        }
+    };
+
+    match listener.accept().await {
+        Ok((stream, _)) => {
+            let stream = stream.into_std().and_then(|s| s.set_nonblocking(false).map(|_| s));
+            match stream {
+                Ok(stream) => sender.send((Commands::HandOff(stream), Reply::default())).expect("failed to send handoff"),
+                Err(e) => error!("Failed to accept handoff connection: {}", e),
+            }
+        }
+        Err(e) => error!("Failed to accept handoff connection: {}", e),
+    }
+}
+
+/// Sends each app's listener to the new daemon, as newline separated app names alongside the fds.
+pub(crate) fn send_listeners(stream: &UnixStream, listeners: &[(String, RawFd)]) -> Result<()> {
+    let names: Vec<&str> = listeners.iter().map(|(name, _)| name.as_str()).collect();
+    let fds: Vec<RawFd> = listeners.iter().map(|(_, fd)| *fd).collect();
+
+    stream.send_with_fd(names.join("\n").as_bytes(), &fds)?;
+    Ok(())
+}
+
+/// Asks the running daemon for its listeners. The old daemon stops accepting once they're sent.
+pub(crate) fn take_over() -> Result<HashMap<String, TcpListener>> {
+    let socket = &settings().handoff_socket;
+    let stream = UnixStream::connect(socket)
+        .with_context(|| format!("no running daemon to take over from at {}", socket))?;
+
+    let mut bytes = vec![0u8; 64 * 1024];
+    let mut fds = [0 as RawFd; MAX_FDS];
+    let (byte_count, fd_count) = stream.recv_with_fd(&mut bytes, &mut fds)?;
+
+    let names = std::str::from_utf8(&bytes[..byte_count])?;
+    let names: Vec<&str> = names.split('\n').filter(|n| !n.is_empty()).collect();
+    if names.len() != fd_count {
+        bail!("received {} listeners for {} apps", fd_count, names.len());
+    }
+
+    Ok(names.into_iter()
+        .zip(fds[..fd_count].iter())
+        .map(|(name, fd)| {
+            info!("Took over listener for '{}' (fd {})", name, fd);
+            (name.to_string(), unsafe { TcpListener::from_raw_fd(*fd) })
+        })
+        .collect())
+}
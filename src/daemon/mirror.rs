@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::*;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::daemon::balance::Pool;
+
+// chunks buffered for a slow mirror before we give up on it
+const MIRROR_BUFFER: usize = 64;
+// how long to wait for the mirror to finish responding once the client is done
+const MIRROR_LINGER: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MirrorConfig {
+    pub(crate) share: f64, // 0.0 - 1.0 of connections copied to the inactive service
+}
+
+impl MirrorConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !self.share.is_finite() {
+            bail!("`mirror.share` has to be a number between 0 and 1");
+        }
+        Ok(())
+    }
+}
+
+/// Picks which connections get mirrored to the inactive service.
+pub(crate) struct Mirror {
+    share: f64,
+    seen: u64,
+    pub(crate) route: Arc<Pool>,
+}
+
+impl Mirror {
+    pub(crate) fn new(config: &MirrorConfig, route: Pool) -> Self {
+        Mirror {
+            share: config.share.clamp(0.0, 1.0),
+            seen: 0,
+            route: Arc::new(route),
+        }
+    }
+
+    /// Spreads mirrored connections evenly, e.g. every tenth connection for a share of 0.1
+    pub(crate) fn sample(&mut self) -> bool {
+        // counted rather than adding up the share, which falls just short of 1 after ten 0.1s
+        let before = (self.seen as f64 * self.share).floor();
+        self.seen += 1;
+        (self.seen as f64 * self.share).floor() > before
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ColorStats {
+    pub(crate) connections: u64,
+    pub(crate) errors: u64,
+}
+
+impl ColorStats {
+    fn record(&mut self, failed: bool) {
+        self.connections += 1;
+        if failed {
+            self.errors += 1;
+        }
+    }
+}
+
+impl fmt::Display for ColorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rate = if self.connections == 0 { 0.0 } else { self.errors as f64 * 100.0 / self.connections as f64 };
+        write!(f, "{} connections, {} errors ({:.2}%)", self.connections, self.errors, rate)
+    }
+}
+
+/// Connection errors per color, for live traffic and mirrored traffic separately.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) live: HashMap<String, ColorStats>,
+    pub(crate) mirrored: HashMap<String, ColorStats>,
+}
+
+impl Metrics {
+    pub(crate) fn record_live(&mut self, color: &str, failed: bool) {
+        self.live.entry(color.to_string()).or_default().record(failed);
+    }
+
+    pub(crate) fn record_mirrored(&mut self, color: &str, failed: bool) {
+        self.mirrored.entry(color.to_string()).or_default().record(failed);
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (color, stats) in &self.live {
+            writeln!(f, "  live {}: {}", color, stats)?;
+        }
+        for (color, stats) in &self.mirrored {
+            writeln!(f, "  mirrored {}: {}", color, stats)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays the client's bytes against `route`, throwing away whatever it responds with.
+/// Returns whether the mirrored connection failed.
+pub(crate) async fn shadow(route: &Pool, mut rx: mpsc::Receiver<Vec<u8>>) -> bool {
+    let lease = route.pick();
+    let stream = match tokio::time::timeout(route.connect_timeout(), TcpStream::connect(lease.backend.addr())).await {
+        Ok(Ok(stream)) => stream,
+        _ => {
+            debug!("Failed to connect to mirror {}", lease.backend.addr());
+            lease.mark_down();
+            return true;
+        }
+    };
+
+    let (mut reader, mut writer) = stream.into_split();
+    let mut discard = tokio::spawn(async move { io::copy(&mut reader, &mut io::sink()).await });
+
+    let mut failed = false;
+    while let Some(chunk) = rx.recv().await {
+        if writer.write_all(&chunk).await.is_err() {
+            failed = true;
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+
+    match tokio::time::timeout(MIRROR_LINGER, &mut discard).await {
+        Ok(Ok(Ok(_))) => failed,
+        Ok(_) => true,
+        Err(_) => {
+            // a mirror that keeps responding hasn't failed, but it isn't worth reading any longer
+            discard.abort();
+            failed
+        }
+    }
+}
+
+pub(crate) fn channel() -> (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) {
+    mpsc::channel(MIRROR_BUFFER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::balance::{HealthConfig, Strategy};
+    use crate::registration::types::Service;
+
+    fn mirror(share: f64) -> Mirror {
+        let service: Service = toml::from_str(
+            "qualified_name = \"green-app\"\nworking_dir = \"/tmp/green-app\"\nport = 9000\non_start = \"app\""
+        ).unwrap();
+        Mirror::new(&MirrorConfig { share }, Pool::new(&service, Strategy::default(), &HealthConfig::default()))
+    }
+
+    fn sampled(mirror: &mut Mirror, connections: usize) -> Vec<usize> {
+        (0..connections).filter(|_| mirror.sample()).collect()
+    }
+
+    #[test]
+    fn spreads_the_share_evenly() {
+        assert_eq!(sampled(&mut mirror(0.1), 30), vec![9, 19, 29]);
+        assert_eq!(sampled(&mut mirror(0.5), 6), vec![1, 3, 5]);
+        assert_eq!(sampled(&mut mirror(0.3), 100).len(), 30);
+    }
+
+    #[test]
+    fn shares_outside_zero_to_one_are_clamped() {
+        assert!(sampled(&mut mirror(0.0), 100).is_empty());
+        assert!(sampled(&mut mirror(-1.0), 100).is_empty());
+        assert_eq!(sampled(&mut mirror(1.0), 5).len(), 5);
+        assert_eq!(sampled(&mut mirror(2.5), 5).len(), 5);
+    }
+
+    #[test]
+    fn the_share_has_to_be_a_number() {
+        assert!(MirrorConfig { share: 0.25 }.validate().is_ok());
+        assert!(MirrorConfig { share: f64::NAN }.validate().is_err());
+        assert!(MirrorConfig { share: f64::INFINITY }.validate().is_err());
+    }
+}
@@ -1,28 +1,53 @@
+pub(crate) mod access;
+pub(crate) mod access_log;
+pub(crate) mod admin;
+pub(crate) mod balance;
+mod activation;
+mod handoff;
+pub(crate) mod mirror;
 mod proxy;
 
+use crate::daemon::access_log::AccessLog;
+use crate::daemon::mirror::Mirror;
+use crate::daemon::balance::Pool;
 use crate::daemon::proxy::Proxy;
 use crate::App;
+use crate::hooks::{self, Hook};
+use crate::settings::settings;
+use crate::supervisor::SupervisorKind;
+use crate::supervisor::builtin::{self, Watchdog};
 use futures::executor::block_on;
 use hotwatch::{Hotwatch};
 use std::collections::HashMap;
-use std::fs;
-use std::fs::DirEntry;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
 use std::path::{PathBuf};
-use std::str::FromStr;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{mpsc, Arc};
-use tokio::fs::File;
-use tokio::io::AsyncBufReadExt;
-use tokio::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
 use tokio::time;
+use std::time::{Duration, Instant};
 use log::*;
 use hotwatch::notify::DebouncedEvent;
 use anyhow::*;
+use sd_notify::NotifyState;
 
 // TODO: remove unnecessary unwraps (you know, do _actual_ error handling)
 
-pub(crate) const FIFO: &str = "/var/tmp/dorc-fifo";
-const APPS_DIR: &str = "/etc/dorc/apps/";
+// how often services run by the built-in supervisor are checked on
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+// how long a release dir has to go unchanged before an upload counts as finished and is copied
+const RELEASE_QUIET: Duration = Duration::from_secs(5);
+
+// run off the command loop, and what's run back on it once they're done, see `Daemon::run_job`
+type Job = Box<dyn FnOnce() + Send>;
+type Done = Box<dyn FnOnce(&mut Daemon) + Send>;
 
 struct ProxiedApp {
     app: App,
@@ -30,56 +55,170 @@ struct ProxiedApp {
 }
 
 impl ProxiedApp {
-    fn from_app(app: App) -> Result<ProxiedApp> {
-        let service_port = app.active_service.port;
-        let res_proxy = block_on(Proxy::new(app.listen_port, service_port))?;
+    fn from_app(app: App, listener: Option<std::net::TcpListener>) -> Result<ProxiedApp> {
+        let route = Pool::new(app.active_service(), app.balance, &app.health);
+        let mut res_proxy = block_on(Proxy::new(app.listen_address(), route, listener))?;
+        res_proxy.access_log = open_access_log(&app);
+        res_proxy.access.reconfigure(app.access.clone().unwrap_or_default());
+        res_proxy.mirror = mirror_for(&app);
+
         let proxy = Arc::new(Mutex::new(res_proxy));
 
         Ok(Self { app, proxy })
     }
+
+    /// Applies a changed app config to the running proxy, without rebinding its listener.
+    /// Returns the config it replaces.
+    fn reconfigure(&mut self, app: App) -> App {
+        let mut proxy = block_on(self.proxy.lock());
+
+        if app.listen_address() != self.app.listen_address() {
+            warn!("{} now listens on {}, restart dorc to rebind", app.app_name, app.listen_address());
+        }
+
+        if app.access_log != self.app.access_log {
+            proxy.access_log = open_access_log(&app);
+        }
+
+        proxy.access.reconfigure(app.access.clone().unwrap_or_default());
+        proxy.reroute_to(Pool::new(app.active_service(), app.balance, &app.health));
+        proxy.mirror = mirror_for(&app);
+
+        drop(proxy);
+        std::mem::replace(&mut self.app, app)
+    }
+}
+
+fn mirror_for(app: &App) -> Option<Mirror> {
+    let standby = app.standby_service()?;
+    app.mirror.as_ref().map(|config| Mirror::new(config, Pool::new(standby, app.balance, &app.health)))
+}
+
+fn open_access_log(app: &App) -> Option<Arc<std::sync::Mutex<AccessLog>>> {
+    let config = app.access_log.clone()?;
+    match AccessLog::open(config) {
+        Ok(access_log) => Some(Arc::new(std::sync::Mutex::new(access_log))),
+        Err(e) => {
+            error!("Access log disabled for {}: {}", app.app_name, e);
+            None
+        }
+    }
 }
 
 struct Daemon {
     apps: HashMap<PathBuf, ProxiedApp>,
+    inherited: HashMap<String, std::net::TcpListener>,
     hotwatch: Hotwatch,
-    cmd_tx: Sender<Commands>,
-    cmd_rx: Receiver<Commands>
+    cmd_tx: Sender<(Commands, Reply)>,
+    cmd_rx: Receiver<(Commands, Reply)>,
+    jobs: Sender<Job>,
+    done_tx: Sender<Done>,
+    done_rx: Receiver<Done>,
+    running_jobs: usize,
+    fifo_task: Option<JoinHandle<()>>,
+    // kept to hand off, admin_task serves a copy of it
+    admin_listener: Option<std::net::TcpListener>,
+    admin_task: Option<JoinHandle<()>>,
+    // once handed off, when to give up on the connections still open
+    drain_deadline: Option<Instant>,
+    watchdog: Watchdog,
+    last_watched: Instant,
+    // apps whose release dir changed, and when it last did
+    uploads: Arc<std::sync::Mutex<HashMap<PathBuf, Instant>>>,
 }
 
 impl Daemon {
     fn new() -> Self {
         let (sender, receiver) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        // starting and stopping services can take minutes, so that's done here, one job after another
+        let (jobs, queued) = mpsc::channel::<Job>();
+        tokio::task::spawn_blocking(move || queued.into_iter().for_each(|job| job()));
 
         let hotwatch = Hotwatch::new().expect("hotwatch failed to initialize!");
 
         Daemon {
             apps: HashMap::new(),
+            inherited: activation::inherited_listeners(),
             hotwatch,
             cmd_tx: sender,
-            cmd_rx: receiver
+            cmd_rx: receiver,
+            jobs,
+            done_tx,
+            done_rx,
+            running_jobs: 0,
+            fifo_task: None,
+            admin_listener: None,
+            admin_task: None,
+            drain_deadline: None,
+            watchdog: Watchdog::default(),
+            last_watched: Instant::now(),
+            uploads: Arc::default(),
         }
     }
 
     fn load_all_apps(&mut self) {
-        // get all ok files in dir
-        let app_files: Vec<DirEntry> = fs::read_dir(APPS_DIR)
-            .unwrap()
-            .filter_map(|p| p.ok())
-            .filter(|p| p.path().is_file())
-            .collect();
+        let app_paths = match settings().app_paths() {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("Could not read {} | {}", settings().apps_dir, e);
+                return;
+            }
+        };
 
-        for app in app_files {
-            self.load_app(app.path());
+        for path in app_paths {
+            self.load_app(path, Reply::default());
         }
     }
 
-    fn hotwatch_release(&mut self, path: PathBuf) {
-        let sender = self.cmd_tx.clone();
-        let result = self.hotwatch.watch(path.clone(), move |event| {
+    /// Runs `job` off the command loop once the jobs queued before it are done,
+    /// then `then` back on the loop with what it returned.
+    fn run_job<T, J, F>(&mut self, job: J, then: F)
+    where
+        T: Send + 'static,
+        J: FnOnce() -> T + Send + 'static,
+        F: FnOnce(&mut Daemon, T) + Send + 'static,
+    {
+        let done = self.done_tx.clone();
+        self.running_jobs += 1;
+        let _ = self.jobs.send(Box::new(move || {
+            let result = job();
+            let _ = done.send(Box::new(move |daemon: &mut Daemon| then(daemon, result)));
+        }));
+    }
+
+    /// Finishes the jobs that are done, without waiting for the rest.
+    fn finish_jobs(&mut self) {
+        for done in self.done_rx.try_iter().collect::<Vec<_>>() {
+            self.running_jobs -= 1;
+            done(self);
+        }
+    }
+
+    /// Waits for every job, including any they queue when they're finished.
+    fn wait_for_jobs(&mut self) {
+        while self.running_jobs > 0 {
+            match tokio::task::block_in_place(|| self.done_rx.recv()) {
+                Ok(done) => {
+                    self.running_jobs -= 1;
+                    done(self);
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Notes uploads to an app's release dir, see `copy_finished_uploads`. `path` is the app's config.
+    fn hotwatch_release(&mut self, path: PathBuf, release_dir: &str) {
+        let uploads = self.uploads.clone();
+        let result = self.hotwatch.watch(release_dir, move |event| {
             match event {
                 DebouncedEvent::Error(e, p) => error!("error while watching {:?}: {}", p, e),
-                DebouncedEvent::Rescan => {}
-                _ => sender.send(Commands::CopyRelease(path.clone())).unwrap()
+                DebouncedEvent::Rescan | DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => {}
+                _ => {
+                    uploads.lock().unwrap().insert(path.clone(), Instant::now());
+                }
             }
         });
 
@@ -88,23 +227,83 @@ impl Daemon {
         }
     }
 
-    fn recv_commands(&mut self) {
+    async fn recv_commands(&mut self) {
         // TODO: should try_recv() be in a loop? or could that cause unwanted latency?
-        if let Ok(command) = self.cmd_rx.try_recv() {
+        if let Ok((command, reply)) = self.cmd_rx.try_recv() {
+            if self.drain_deadline.is_some() {
+                debug!("Ignoring {:?}, this daemon has been handed off", command);
+                return;
+            }
+
             info!("Received {:?}", command);
             match command {
-                Commands::Reload(name) => self.reload_app(app_pathbuf(name)),
-                Commands::Load(name) => self.load_app(app_pathbuf(name)),
-                Commands::Switch(name) => block_on(self.switch_active(app_pathbuf(name))),
-                Commands::CopyRelease(path) => self.copy_release(PathBuf::from(path))
+                Commands::HandOff(stream) => {
+                    // anything sent before the new daemon took over is still ours to run
+                    self.stop_reading_commands().await;
+                    for (command, reply) in self.cmd_rx.try_iter().collect::<Vec<_>>() {
+                        match command {
+                            Commands::Upgrade => reply.send(Err(anyhow!("Ignoring {:?}, a new daemon is already taking over", command))),
+                            command => {
+                                info!("Received {:?} before handing off", command);
+                                self.run_command(command, reply).await;
+                            }
+                        }
+                    }
+                    // the new daemon loads apps as they are once these are done, e.g. with a new release
+                    if self.running_jobs > 0 {
+                        info!("Waiting for {} jobs before handing off", self.running_jobs);
+                        self.wait_for_jobs();
+                    }
+                    self.hand_off(stream).await;
+                }
+                command => self.run_command(command, reply).await,
             }
         }
     }
 
+    async fn run_command(&mut self, command: Commands, reply: Reply) {
+        match command {
+            Commands::Reload(name) => self.reload_app(app_pathbuf(name), reply),
+            Commands::Load(name) => self.load_app(app_pathbuf(name), reply),
+            Commands::Switch(name, slot, grace) => reply.send(self.switch_active(app_pathbuf(name), slot, grace).await),
+            Commands::CopyRelease(path) => self.copy_release(path, reply),
+            Commands::Stats(name) => reply.send(self.log_stats(app_pathbuf(name)).await),
+            Commands::Upgrade => reply.send(self.upgrade()),
+            Commands::HandOff(_) => reply.send(Err(anyhow!("Ignoring a second handoff, one is already under way"))),
+        }
+    }
+
     async fn listen(&mut self) {
-        self.recv_commands();
+        self.recv_commands().await;
+        self.finish_jobs();
 
-        for (_, app) in &mut self.apps {
+        if let Some(deadline) = self.drain_deadline {
+            let mut remaining = 0;
+            for app in self.apps.values() {
+                remaining += app.proxy.lock().await.connections.load(Ordering::SeqCst);
+            }
+
+            if remaining == 0 {
+                info!("All connections drained, exiting.");
+                std::process::exit(0);
+            }
+            if Instant::now() >= deadline {
+                warn!("Closing {} connections that outlasted the drain timeout, exiting.", remaining);
+                std::process::exit(0);
+            }
+            return;
+        }
+
+        self.copy_finished_uploads();
+
+        if self.last_watched.elapsed() >= WATCHDOG_INTERVAL {
+            self.last_watched = Instant::now();
+            for app in self.apps.values().filter(|a| a.app.supervisor == SupervisorKind::Builtin) {
+                self.watchdog.check(&app.app);
+            }
+        }
+
+        for app in self.apps.values() {
             if !app.proxy.lock().await.is_listening {
                 let tmp1 = app.proxy.clone();
                 tokio::spawn(async move {
@@ -115,61 +314,300 @@ impl Daemon {
         }
     }
 
-    fn load_app(&mut self, path: PathBuf) {
+    /// Copies releases once their upload has stopped changing files, rather than a partial release per file written.
+    fn copy_finished_uploads(&mut self) {
+        let finished: Vec<PathBuf> = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let now = Instant::now();
+            let finished = uploads.iter()
+                .filter(|(_, changed)| now.duration_since(**changed) >= RELEASE_QUIET)
+                .map(|(path, _)| path.clone())
+                .collect();
+            uploads.retain(|_, changed| now.duration_since(*changed) < RELEASE_QUIET);
+            finished
+        };
+
+        for path in finished {
+            let _ = self.cmd_tx.send((Commands::CopyRelease(path), Reply::default()));
+        }
+    }
+
+    fn load_app(&mut self, path: PathBuf, reply: Reply) {
         info!("Loading app: {}", path.to_str().unwrap());
 
-        let res_app = App::load(&path);
+        let mut app = match App::load(&path) {
+            Ok(app) => app,
+            Err(e) => return reply.send(Err(anyhow!("Could not load file {:?} as app | {}", path.file_name(), e))),
+        };
+
+        let allocated = match app.allocate_ports() {
+            Ok(allocated) => allocated,
+            Err(e) => return reply.send(Err(anyhow!("Could not pick ports for {} | {}", app.app_name, e))),
+        };
+
+        let moved_bins = match app.migrate_legacy_bins() {
+            Ok(moved) => moved,
+            Err(e) => return reply.send(Err(anyhow!("Could not move {}'s binaries out of /usr/local/bin | {}", app.app_name, e))),
+        };
+        // after moving the binaries, since an upgraded config no longer says which slots had them
+        if app.upgraded_from.is_some() {
+            app.write_upgraded(&path);
+        } else if allocated {
+            app.write_allocated(&path);
+        } else if moved_bins {
+            app.save();
+        }
+
+        let units = app.clone();
+        let previous = self.apps.get(&path).map(|proxied_app| proxied_app.app.clone());
+        self.run_job(move || {
+            let synced = units.sync_units(previous.as_ref());
+            if units.supervisor == SupervisorKind::Builtin {
+                builtin::start_enabled(&units);
+            }
+            synced
+        }, move |daemon, synced| daemon.route_loaded(path, app, synced, reply));
+    }
+
+    /// Sends traffic to a loaded app, once its services have been started.
+    fn route_loaded(&mut self, path: PathBuf, app: App, synced: Result<()>, reply: Reply) {
+        let synced = synced.map_err(|e| anyhow!("Failed to regenerate units for {}: {}", app.app_name, e));
 
-        if let Err(e) = res_app {
-            error!("Could not load file {:?} as app | {}", path.file_name(), e);
-            return
+        if let Some(proxied_app) = self.apps.get_mut(&path) {
+            let previous = proxied_app.reconfigure(app);
+            info!("Reloaded config for {}", proxied_app.app.app_name);
+
+            // only once traffic has moved to the services that replace them
+            let current = proxied_app.app.clone();
+            self.run_job(move || current.remove_stale_units(&previous), move |_, removed| {
+                let removed = removed.map_err(|e| anyhow!("Failed to remove old services: {}", e));
+                reply.send(synced.and(removed));
+            });
+            return;
         }
 
-        let res_proxied_app = ProxiedApp::from_app(res_app.unwrap());
+        // prefer a socket passed in by systemd, so the listener outlives the daemon
+        let listener = self.inherited.get(&app.app_name).and_then(|l| l.try_clone().ok());
+        if app.socket_activated && listener.is_none() {
+            warn!("No socket was passed in for {}, binding {} directly", app.app_name, app.listen_address());
+        }
+
+        let proxied_app = match ProxiedApp::from_app(app, listener) {
+            Ok(proxied_app) => proxied_app,
+            Err(e) => return reply.send(Err(anyhow!("Could not create ProxiedApp: {}", e))),
+        };
+
+        let release_dir = proxied_app.app.release_dir.clone();
+        self.apps.insert(path.clone(), proxied_app); // ignore old value
+        self.hotwatch_release(path, &release_dir);
+        reply.send(synced);
+    }
+
+    fn reload_app(&mut self, path: PathBuf, reply: Reply) {
+        let app = match self.apps.get(&path) {
+            Some(proxied_app) => proxied_app.app.clone(),
+            None => return reply.send(Err(anyhow!("Failed to reload app from path: {:?}", path))),
+        };
+
+        self.run_job(move || {
+            let mut failed = vec![];
+            for service in app.active_service().instances() {
+                match app.supervisor().reload(&app, &service) {
+                    Ok(_) => info!("'{}' has been reloaded.", service.qualified_name),
+                    Err(e) => {
+                        error!("Failed to reload '{}': {}", service.qualified_name, e);
+                        failed.push(service.qualified_name);
+                    }
+                }
+            }
+            failed
+        }, move |_, failed| match failed.is_empty() {
+            true => reply.send(Ok(())),
+            false => reply.send(Err(anyhow!("Failed to reload {}", failed.join(", ")))),
+        });
+    }
 
-        if let Err(e) = res_proxied_app {
-            error!("Could not create ProxiedApp: {}", e);
-            return
+    fn copy_release(&mut self, path: PathBuf, reply: Reply) {
+        let app = match self.apps.get(&path) {
+            Some(proxied_app) => &proxied_app.app,
+            None => return reply.send(Err(anyhow!("Not copying a release for {}, it isn't loaded", path.display()))),
+        };
+
+        let slot = match app.ingest_slot() {
+            Some(slot) => slot,
+            None => return reply.send(Err(anyhow!("{} has no inactive slot to copy the release to", app.app_name))),
+        };
+
+        let mut copy = app.clone();
+        let release_id = crate::new_release_id();
+        copy.slots[slot].release_id = Some(release_id.clone());
+
+        self.run_job(move || {
+            let service = &copy.slots[slot];
+            copy.migrate_service(service).map(|_| service.qualified_name.clone())
+        }, move |daemon, copied| reply.send(daemon.record_release(path, release_id, copied)));
+    }
+
+    /// Makes a copied release the one `dorc switch` picks next, and the one traffic is mirrored to.
+    fn record_release(&mut self, path: PathBuf, release_id: String, copied: Result<String>) -> Result<()> {
+        let proxied_app = self.apps.get_mut(&path).ok_or_else(|| anyhow!("{} was unloaded", path.display()))?;
+        let app = &mut proxied_app.app;
+        // a failed release is left out, so it isn't picked
+        let slot = copied.map_err(|e| anyhow!("Failed to copy release directory for {}: {}", app.app_name, e))?;
+
+        match app.slots.iter_mut().find(|s| s.qualified_name == slot) {
+            Some(service) => service.release_id = Some(release_id),
+            None => bail!("{} no longer has a {} slot to record its release in", app.app_name, slot),
         }
+        app.save();
 
-        self.apps.insert(path.clone(), res_proxied_app.unwrap()); // ignore old value
-        self.hotwatch_release(path.clone());
+        block_on(proxied_app.proxy.lock()).mirror = mirror_for(&proxied_app.app);
+        Ok(())
     }
 
-    fn reload_app(&mut self, path: PathBuf) {
-        let opt_app = self.apps.get(&path);
-        if let None = opt_app {
-            error!("Failed to reload app from path: {:?}", path);
+    /// Takes commands on `address` too, on the listener the old daemon handed off if there is one.
+    fn listen_for_admins(&mut self, address: SocketAddr) {
+        let listener = match self.inherited.remove(admin::HANDOFF_NAME) {
+            Some(listener) => Ok(listener),
+            None => std::net::TcpListener::bind(address),
+        };
+
+        match listener {
+            Ok(listener) => {
+                info!("Listening for admin commands on {}", address);
+                self.admin_listener = Some(listener);
+            }
+            Err(e) => error!("Could not listen for admin commands on {}: {}", address, e),
+        }
+    }
+
+    /// Reads commands from the FIFO, and from admins if there's a listener for them.
+    fn read_commands(&mut self) {
+        self.fifo_task = Some(tokio::spawn(watch_fifo(self.cmd_tx.clone())));
+
+        // the listener itself is kept, to hand off
+        match self.admin_listener.as_ref().map(|l| l.try_clone()) {
+            Some(Ok(listener)) => self.admin_task = Some(tokio::spawn(admin::serve(listener, self.cmd_tx.clone()))),
+            Some(Err(e)) => error!("Could not listen for admin commands: {}", e),
+            None => {}
+        }
+    }
+
+    /// Stops reading the FIFO, which closes our end, and closes admin connections.
+    /// Once this returns, nothing else is sent to `cmd_rx`.
+    async fn stop_reading_commands(&mut self) {
+        for task in self.fifo_task.take().into_iter().chain(self.admin_task.take()) {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+
+    fn upgrade(&mut self) -> Result<()> {
+        let result = crate::own_exe().and_then(|exe| {
+            let mut command = std::process::Command::new(exe);
+            // the new daemon reads the same settings, even if they were given on the command line
+            if let Some(config) = &settings().path {
+                command.arg("--config").arg(config);
+            }
+            if let Some(root) = &settings().root {
+                command.arg("--root").arg(root);
+            }
+            if settings().user_mode {
+                command.arg("--user");
+            }
+            command.args(["start-daemon", "--takeover"]).spawn()
+        });
+
+        let child = result.map_err(|e| anyhow!("Failed to start new daemon: {}", e))?;
+        info!("Started new daemon (pid {}), waiting for it to take over.", child.id());
+        Ok(())
+    }
+
+    async fn hand_off(&mut self, stream: UnixStream) {
+        let mut listeners = vec![];
+        for app in self.apps.values() {
+            let proxy = app.proxy.lock().await;
+            listeners.push((app.app.app_name.clone(), proxy.listener.as_raw_fd()));
+        }
+        if let Some(admin) = &self.admin_listener {
+            listeners.push((admin::HANDOFF_NAME.to_string(), admin.as_raw_fd()));
+        }
+
+        if let Err(e) = handoff::send_listeners(&stream, &listeners) {
+            error!("Failed to hand off listeners: {}", e);
+            self.read_commands();
+            tokio::spawn(handoff::serve(self.cmd_tx.clone()));
             return;
         }
+        self.admin_listener = None;
 
-        let app = &opt_app.unwrap().app;
-        std::process::Command::new("systemctl")
-            .args(&["reload", &app.active_service.qualified_name])
-            .output()
-            .expect("failed to enable");
+        let mut remaining = 0;
+        for app in self.apps.values() {
+            let mut proxy = app.proxy.lock().await;
+            proxy.draining = true;
+            remaining += proxy.connections.load(Ordering::SeqCst);
+        }
 
-        info!("'{}' has been reloaded.", app.active_service.qualified_name);
+        let timeout = settings().drain_timeout;
+        self.drain_deadline = Some(Instant::now() + timeout);
+        info!("Handed off {} listeners, draining {} connections for up to {}.",
+            listeners.len(), remaining, humantime::format_duration(timeout));
+    }
 
+    async fn log_stats(&self, path: PathBuf) -> Result<()> {
+        let proxied_app = self.apps.get(&path).ok_or_else(|| anyhow!("Could not retrieve app from {}", path.display()))?;
+        let proxy = proxied_app.proxy.lock().await;
+        let metrics = proxy.metrics.lock().unwrap();
+        info!("Connection stats for {} (active: {}):\n{}", proxied_app.app.app_name, proxy.route.color, metrics);
+        Ok(())
     }
 
-    fn copy_release(&mut self, path: PathBuf) {
-        let proxied_app = self.apps.get(&path).expect("Failed to copy release from an unloaded application");
-        let app = &proxied_app.app;
+    async fn switch_active(&mut self, path: PathBuf, slot: Option<String>, grace: Option<Duration>) -> Result<()> {
+        let proxied_app = self.apps.get_mut(&path).ok_or_else(|| anyhow!("Could not retrieve app from {}", path.display()))?;
+        let app = &mut proxied_app.app;
+        let target = app.switch_target(slot.as_deref()).map_err(|e| anyhow!("Could not switch: {}", e))?;
+
+        if let Err(e) = hooks::run(app, app.slot(&target).unwrap(), Hook::PreSwitch) {
+            bail!("Not switching {} to {}: {}", app.app_name, target, e);
+        }
+
+        let previous = std::mem::replace(&mut app.active, target);
+        info!("{} switched from {} to {}", app.app_name, previous, app.active);
+
+        let mut proxy = proxied_app.proxy.lock().await;
+        proxy.reroute_to(Pool::new(app.active_service(), app.balance, &app.health));
+        proxy.mirror = mirror_for(app);
+        let generation = proxy.generation;
+        drop(proxy);
+        app.save();
+
+        if let Some(grace) = grace.or(app.grace_period).or(settings().defaults.grace_period) {
+            info!("Connections to {} will be closed in {}", previous, humantime::format_duration(grace));
+            tokio::spawn(Proxy::evict_after(proxied_app.proxy.clone(), previous, grace, generation));
+        }
 
-        // copy files from inactive service
-        if let Err(e) = app.migrate_service(&app.inactive_service) {
-            error!("Failed to copy release directory for {}: {}", app.app_name, e);
+        if let Err(e) = hooks::run(app, app.active_service(), Hook::PostSwitch) {
+            bail!("{} switched, but: {}", app.app_name, e);
         }
+        Ok(())
     }
-    async fn switch_active(&mut self, path: PathBuf) {
-        if let Some(proxied_app) = self.apps.get_mut(&path) {
-            let (app, proxy) = (&mut proxied_app.app, &mut proxied_app.proxy.lock().await);
-            app.swap_active();
-            proxy.reroute_to(app.active_service.port);
-            app.save();
-        } else {
-            error!("Could not retrieve app from {}", path.to_str().unwrap())
+}
+
+/// Where a command's outcome goes besides the log: back to the admin who sent it, if one did.
+#[derive(Debug, Default)]
+pub(crate) struct Reply(Option<oneshot::Sender<Result<(), String>>>);
+
+impl Reply {
+    pub(crate) fn to(sender: oneshot::Sender<Result<(), String>>) -> Reply {
+        Reply(Some(sender))
+    }
+
+    fn send(self, result: Result<()>) {
+        if let Err(e) = &result {
+            error!("{}", e);
+        }
+        if let Some(sender) = self.0 {
+            let _ = sender.send(result.map_err(|e| e.to_string()));
         }
     }
 }
@@ -178,15 +616,35 @@ impl Daemon {
 pub enum Commands {
     Reload(String),
     Load(String),
-    Switch(String),
+    Switch(String, Option<String>, Option<Duration>),
+    Stats(String),
     CopyRelease(PathBuf),
+    Upgrade,
+    HandOff(UnixStream),
 }
 
-pub async fn start() {
+pub async fn start(takeover: bool) {
     let mut daemon = Daemon::new();
+
+    if takeover {
+        match handoff::take_over() {
+            Ok(listeners) => daemon.inherited.extend(listeners),
+            Err(e) => error!("Could not take over from the running daemon: {}", e),
+        }
+    }
+
     daemon.load_all_apps();
 
-    tokio::spawn(watch_fifo(daemon.cmd_tx.clone()));
+    if let Some(address) = settings().admin_address {
+        daemon.listen_for_admins(address);
+    }
+    daemon.read_commands();
+    tokio::spawn(handoff::serve(daemon.cmd_tx.clone()));
+
+    // lets systemd track us as the main process, even when we took over from an older daemon
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::MainPid(std::process::id()), NotifyState::Ready]) {
+        error!("Failed to notify systemd: {}", e);
+    }
 
     let mut interval = time::interval(time::Duration::from_millis(20));
 
@@ -197,64 +655,120 @@ pub async fn start() {
 }
 
 fn app_pathbuf(app_name: String) -> PathBuf {
-    PathBuf::from_str(&format!("{}{}.toml", APPS_DIR, app_name)).unwrap()
+    settings().app_path(&app_name)
 }
 
-pub(crate) async fn watch_fifo(sender: mpsc::Sender<Commands>) {
+pub(crate) async fn watch_fifo(sender: mpsc::Sender<(Commands, Reply)>) {
     debug!("Watching FIFO command file...");
-    let _ = unix_named_pipe::create(FIFO, None);
-
-    let fd = File::open(FIFO).await.expect(&format!("Failed to open FIFO file descriptor {}", FIFO));
-
-    let mut reader = tokio::io::BufReader::with_capacity(128, fd);
-    let mut buf = String::new();
-
-    let mut interval = time::interval(time::Duration::from_secs(5));
+    let fifo = &settings().fifo;
+    let _ = crate::settings::create_parent(fifo);
+    let _ = unix_named_pipe::create(fifo, None);
+
+    // read-write, so no writer being left never reads as the end of the file, and non-blocking,
+    // so aborting this task when handing off closes the FIFO instead of leaving a read behind that takes one more command
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(fifo)
+        .and_then(AsyncFd::new);
+    let fd = match file {
+        Ok(fd) => fd,
+        Err(e) => {
+            error!("Failed to open FIFO {}: {}", fifo, e);
+            return;
+        }
+    };
 
+    let mut pending = String::new();
+    let mut bytes = [0u8; 128];
     loop {
-        interval.tick().await;
-        let bytes_read = reader.read_line(&mut buf).await.unwrap();
+        let mut guard = match fd.readable().await {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Failed to read FIFO {}: {}", fifo, e);
+                return;
+            }
+        };
+        match guard.try_io(|inner| inner.get_ref().read(&mut bytes)) {
+            Ok(Ok(read)) => pending.push_str(&String::from_utf8_lossy(&bytes[..read])),
+            Ok(Err(e)) => {
+                error!("Failed to read FIFO {}: {}", fifo, e);
+                return;
+            }
+            Err(_would_block) => continue,
+        }
 
-        if bytes_read != 0 {
-            let splitbuf: Vec<&str> = buf.splitn(2, " ").collect();
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            match parse_command(&line) {
+                Ok(Some(command)) => sender.send((command, Reply::default())).expect("failed to send command"),
+                Ok(None) => {}
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+}
 
-            let command = {
-                if splitbuf.len() >= 1 {
-                    splitbuf[0]
-                } else {
-                    buf.clear();
-                    continue;
-                }
-            };
+/// A line written to the FIFO, or `None` if it's blank.
+fn parse_command(line: &str) -> Result<Option<Commands>, String> {
+    let splitbuf: Vec<&str> = line.splitn(2, ' ').collect();
+    let command = splitbuf[0].trim();
+    let name = || match splitbuf.get(1).map(|n| n.trim()) {
+        Some(name) if !name.is_empty() => Ok(name.to_string()),
+        _ => Err(format!("Wrong number of arguments on {} command.", command)),
+    };
+
+    match command {
+        "" => Ok(None),
+        "reload" => Ok(Some(Commands::Reload(name()?))),
+        "load" => Ok(Some(Commands::Load(name()?))),
+        "stats" => Ok(Some(Commands::Stats(name()?))),
+        "upgrade" => Ok(Some(Commands::Upgrade)),
+        "switch" => {
+            // switch <name> [to=<slot>] [grace]
+            let args: Vec<&str> = splitbuf.get(1).map(|a| a.split_whitespace().collect()).unwrap_or_default();
+            let slot = args.iter().find_map(|a| a.strip_prefix("to=")).map(|s| s.to_string());
+            let grace = args.iter().skip(1).find(|a| !a.starts_with("to=")).map(|g| humantime::parse_duration(g));
+
+            match grace {
+                Some(Err(e)) => Err(format!("Invalid grace period on switch command: {}", e)),
+                _ if (1..=3).contains(&args.len()) => Ok(Some(Commands::Switch(args[0].to_string(), slot, grace.and_then(|g| g.ok())))),
+                _ => Err("Wrong number of arguments on switch command.".to_string()),
+            }
+        }
+        _ => Err(format!("{} is not a valid command.", command)),
+    }
+}
 
-            match command {
-                "reload" => {
-                    if splitbuf.len() == 2 {
-                        sender.send(Commands::Reload(splitbuf[1].trim().to_string()))
-                            .expect("failed to send reload");
-                    } else {
-                        error!("Wrong number of arguments on reload command.");
-                    }
-                }
-                "load" => {
-                    if splitbuf.len() == 2 {
-                        sender.send(Commands::Load(splitbuf[1].trim().to_string()))
-                            .expect("failed to send load");
-                    } else {
-                        error!("Wrong number of arguments on load command.");
-                    }
-                }
-                "switch" => {
-                    if splitbuf.len() == 2 {
-                        sender.send(Commands::Switch(splitbuf[1].trim().to_string()))
-                            .expect("failed to send switch");
-                    } else {
-                        error!("Wrong number of arguments on switch command.");
-                    }
-                }
-                _ => error!("{} is not a valid command.", command)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switch_takes_a_slot_and_a_grace_period_in_any_order() {
+        match parse_command("switch app to=green 90000ms\n") {
+            Ok(Some(Commands::Switch(name, slot, grace))) => {
+                assert_eq!(name, "app");
+                assert_eq!(slot.as_deref(), Some("green"));
+                assert_eq!(grace, Some(Duration::from_secs(90)));
             }
-            buf.clear();
+            other => panic!("unexpected {:?}", other),
         }
+        assert!(matches!(parse_command("switch app 30s to=red"), Ok(Some(Commands::Switch(_, Some(_), Some(_))))));
+    }
+
+    #[test]
+    fn switch_rejects_a_split_grace_period() {
+        assert!(parse_command("switch app 1m 30s to=red extra").is_err());
+        assert!(parse_command("switch app soon").is_err());
+    }
+
+    #[test]
+    fn commands_need_their_app() {
+        assert!(parse_command("load\n").is_err());
+        assert!(matches!(parse_command("load app\n"), Ok(Some(Commands::Load(_)))));
+        assert!(matches!(parse_command("\n"), Ok(None)));
+        assert!(parse_command("bogus app").is_err());
     }
 }
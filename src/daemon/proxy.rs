@@ -1,33 +1,98 @@
 use tokio::io;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
-use futures::FutureExt;
 use log::*;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, watch, Mutex};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use anyhow::*;
 
+use crate::daemon::access::{AccessPolicy, Verdict};
+use crate::daemon::access_log::{AccessLog, CloseReason, ConnectionRecord};
+use crate::daemon::balance::Pool;
+use crate::daemon::mirror::{self, Metrics, Mirror};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Backend {
+    pub(crate) port: u16,
+}
+
+impl Backend {
+    pub(crate) fn addr(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+}
+
 pub(crate) struct Proxy {
     pub(crate) listener: TcpListener,
-    pub(crate) route: String,
+    pub(crate) route: Arc<Pool>,
     pub(crate) is_listening: bool,
+    pub(crate) draining: bool,
+    pub(crate) connections: Arc<AtomicUsize>,
+    pub(crate) access_log: Option<Arc<std::sync::Mutex<AccessLog>>>,
+    pub(crate) access: AccessPolicy,
+    pub(crate) mirror: Option<Mirror>,
+    pub(crate) metrics: Arc<std::sync::Mutex<Metrics>>,
+    // bumped on every switch, so a stale eviction doesn't fire
+    pub(crate) generation: u64,
+    evict_tx: watch::Sender<Option<String>>,
+    evict_rx: watch::Receiver<Option<String>>, // held so sends never fail
 }
 
 // largely taken from tokio's proxy example
 impl Proxy {
-    pub async fn new(listener_port: u16, server_port: u16) -> Result<Proxy> {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", listener_port)).await?;
+    pub async fn new(listen_address: SocketAddr, route: Pool, inherited: Option<std::net::TcpListener>) -> Result<Proxy> {
+        let listener = match inherited {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(listen_address).await?
+        };
+        let (evict_tx, evict_rx) = watch::channel(None);
         Ok(Proxy {
             listener,
-            route: format!("127.0.0.1:{}", server_port),
-            is_listening: false
+            route: Arc::new(route),
+            is_listening: false,
+            draining: false,
+            connections: Arc::new(AtomicUsize::new(0)),
+            access_log: None,
+            access: AccessPolicy::new(Default::default()),
+            mirror: None,
+            metrics: Default::default(),
+            generation: 0,
+            evict_tx,
+            evict_rx,
         })
     }
 
-    pub fn reroute_to(&mut self, server_port: u16) {
-        self.route = format!("127.0.0.1:{}", server_port);
+    pub fn reroute_to(&mut self, route: Pool) {
+        // keep connection counts and instance health, unless something changed
+        if route.same_as(&self.route) {
+            return;
+        }
+
+        if route.color != self.route.color {
+            self.generation += 1;
+            // connections to the new route may have been evicted by an earlier switch
+            let _ = self.evict_tx.send(None);
+        }
+        self.route = Arc::new(route);
+    }
+
+    /// Closes connections still open to `color` once `grace` has passed, unless we've switched again since.
+    /// `generation` is the one the switch away from `color` made, read while it still held the lock.
+    pub async fn evict_after(s: Arc<Mutex<Proxy>>, color: String, grace: Duration, generation: u64) {
+        tokio::time::sleep(grace).await;
+
+        let guard = s.lock().await;
+        if guard.generation == generation {
+            info!("Grace period over, closing connections to {}", color);
+            let _ = guard.evict_tx.send(Some(color));
+        }
     }
 
     pub async fn listen(s: Arc<Mutex<Proxy>>) {
@@ -35,17 +100,60 @@ impl Proxy {
         // Lock proxy for up to 500ms at a time, allowing it to be modified between loops
         let mut ok = true;
         while ok {
-            let guard = s.lock().await;
+            let mut guard = s.lock().await;
+            if guard.draining {
+                break;
+            }
+
             if let Ok(result) = tokio::time::timeout(Duration::from_millis(500), guard.listener.accept()).await {
                 ok = result.is_ok();
 
-                if let Ok((inbound, _)) = result {
-                    let transfer = transfer(inbound, guard.route.clone()).map(|r| {
-                        if let Err(e) = r {
-                            error!("Failed to transfer; error={}", e);
-                        }
+                if let Ok((inbound, client)) = result {
+                    let reason = match guard.access.check(client.ip()) {
+                        Verdict::Admit => None,
+                        Verdict::Denied => Some(CloseReason::Denied),
+                        Verdict::RateLimited => Some(CloseReason::RateLimited),
+                    };
+
+                    if let Some(reason) = reason {
+                        debug!("Refused connection from {}: {}", client, reason);
+                        log_connection(&guard.access_log, &ConnectionRecord {
+                            client,
+                            color: guard.route.color.clone(),
+                            port: guard.route.ports()[0],
+                            duration: Duration::from_secs(0),
+                            bytes_in: 0,
+                            bytes_out: 0,
+                            reason,
+                        });
+                        continue;
+                    }
+
+                    let connections = guard.connections.clone();
+                    let access_log = guard.access_log.clone();
+                    let route = guard.route.clone();
+                    let evict = guard.evict_rx.clone();
+                    let metrics = guard.metrics.clone();
+
+                    let mirror_route = guard.mirror.as_mut()
+                        .and_then(|mirror| if mirror.sample() { Some(mirror.route.clone()) } else { None });
+                    let tee = mirror_route.map(|mirror_route| {
+                        let (tx, rx) = mirror::channel();
+                        let metrics = metrics.clone();
+                        tokio::spawn(async move {
+                            let failed = mirror::shadow(&mirror_route, rx).await;
+                            metrics.lock().unwrap().record_mirrored(&mirror_route.color, failed);
+                        });
+                        tx
+                    });
+
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        let record = transfer(inbound, client, route, evict, tee).await;
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        metrics.lock().unwrap().record_live(&record.color, record.reason.is_backend_failure());
+                        log_connection(&access_log, &record);
                     });
-                    tokio::spawn(transfer);
                 }
             }
         }
@@ -58,23 +166,216 @@ impl Proxy {
     }
 }
 
-async fn transfer(mut inbound: TcpStream, proxy_addr: String) -> Result<()> {
-    let mut outbound = TcpStream::connect(proxy_addr).await?;
+fn log_connection(access_log: &Option<Arc<std::sync::Mutex<AccessLog>>>, record: &ConnectionRecord) {
+    if let Some(access_log) = access_log {
+        if let Err(e) = access_log.lock().unwrap().record(record) {
+            error!("Failed to write access log; error={}", e);
+        }
+    }
+}
+
+async fn transfer(
+    mut inbound: TcpStream,
+    client: SocketAddr,
+    route: Arc<Pool>,
+    evict: watch::Receiver<Option<String>>,
+    tee: Option<mpsc::Sender<Vec<u8>>>,
+) -> ConnectionRecord {
+    let started = Instant::now();
+    let port = AtomicU16::new(route.ports()[0]);
+    let bytes_in = AtomicU64::new(0);
+    let bytes_out = AtomicU64::new(0);
+
+    let reason = tokio::select! {
+        reason = pipe(&mut inbound, &route, &port, &bytes_in, &bytes_out, tee) => reason,
+        _ = evicted(evict, &route.color) => CloseReason::Evicted,
+    };
+
+    ConnectionRecord {
+        client,
+        color: route.color.clone(),
+        port: port.into_inner(),
+        duration: started.elapsed(),
+        bytes_in: bytes_in.into_inner(),
+        bytes_out: bytes_out.into_inner(),
+        reason,
+    }
+}
+
+async fn evicted(mut evict: watch::Receiver<Option<String>>, color: &str) {
+    loop {
+        if evict.borrow().as_deref() == Some(color) {
+            return;
+        }
+
+        if evict.changed().await.is_err() {
+            // the proxy is gone, let the connection run its course
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Copies bytes both ways until the connection closes, returning which side closed it.
+/// Bytes from the client are also copied to `tee`, if there is one.
+async fn pipe(
+    inbound: &mut TcpStream,
+    route: &Pool,
+    port: &AtomicU16,
+    bytes_in: &AtomicU64,
+    bytes_out: &AtomicU64,
+    tee: Option<mpsc::Sender<Vec<u8>>>,
+) -> CloseReason {
+    // try each instance at most once before giving up
+    let mut attempts = route.len();
+    let (_lease, mut outbound) = loop {
+        let lease = route.pick();
+        port.store(lease.backend.port, Ordering::Relaxed);
+        attempts -= 1;
+
+        let reason = match tokio::time::timeout(route.connect_timeout(), TcpStream::connect(lease.backend.addr())).await {
+            Ok(Ok(outbound)) => break (lease, outbound),
+            Ok(Err(e)) => {
+                error!("Failed to transfer; error={}", e);
+                CloseReason::BackendError
+            }
+            Err(_) => {
+                error!("Failed to transfer; timed out connecting to {}", lease.backend.addr());
+                CloseReason::Timeout
+            }
+        };
+
+        lease.mark_down();
+        if attempts == 0 {
+            return reason;
+        }
+    };
 
     let (mut ri, mut wi) = inbound.split();
     let (mut ro, mut wo) = outbound.split();
 
     let client_to_server = async {
-        io::copy(&mut ri, &mut wo).await?;
-        wo.shutdown().await
+        match copy_counted(&mut ri, &mut wo, bytes_in, tee).await {
+            Ok(_) => Ok(CloseReason::ClientClosed),
+            Err(_) => Err(CloseReason::ClientError),
+        }
     };
 
     let server_to_client = async {
-        io::copy(&mut ro, &mut wi).await?;
-        wi.shutdown().await
+        match copy_counted(&mut ro, &mut wi, bytes_out, None).await {
+            Ok(_) => Ok(CloseReason::BackendClosed),
+            Err(_) => Err(CloseReason::BackendError),
+        }
     };
 
-    tokio::try_join!(client_to_server, server_to_client)?;
+    tokio::pin!(client_to_server, server_to_client);
 
-    Ok(())
+    // whichever side finishes first closed the connection, an error on either side ends it
+    tokio::select! {
+        result = &mut client_to_server => match result {
+            Ok(reason) => server_to_client.await.err().unwrap_or(reason),
+            Err(reason) => reason,
+        },
+        result = &mut server_to_client => match result {
+            Ok(reason) => client_to_server.await.err().unwrap_or(reason),
+            Err(reason) => reason,
+        },
+    }
+}
+
+async fn copy_counted<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    count: &AtomicU64,
+    mut tee: Option<mpsc::Sender<Vec<u8>>>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; 8 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+
+        writer.write_all(&buf[..n]).await?;
+        count.fetch_add(n as u64, Ordering::Relaxed);
+
+        // never hold up the real connection, a mirror that can't keep up is dropped
+        if let Some(sender) = &tee {
+            if sender.try_send(buf[..n].to_vec()).is_err() {
+                tee = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::balance::{HealthConfig, Strategy};
+    use crate::registration::types::Service;
+
+    fn route(color: &str) -> Pool {
+        let service: Service = toml::from_str(&format!(
+            "qualified_name = \"{}-app\"\nworking_dir = \"/tmp/{}-app\"\nport = 9000\non_start = \"app\"",
+            color, color
+        )).unwrap();
+        Pool::new(&service, Strategy::default(), &HealthConfig::default())
+    }
+
+    async fn proxy(color: &str) -> Arc<Mutex<Proxy>> {
+        let proxy = Proxy::new("127.0.0.1:0".parse().unwrap(), route(color), None).await.unwrap();
+        Arc::new(Mutex::new(proxy))
+    }
+
+    /// Whether a connection to `color` would be closed within a moment.
+    async fn is_evicted(proxy: &Arc<Mutex<Proxy>>, color: &str) -> bool {
+        let evict = proxy.lock().await.evict_rx.clone();
+        tokio::time::timeout(Duration::from_millis(50), evicted(evict, color)).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn evicts_the_old_color_after_the_grace_period() {
+        let proxy = proxy("blue").await;
+        let generation = {
+            let mut guard = proxy.lock().await;
+            guard.reroute_to(route("green"));
+            guard.generation
+        };
+
+        Proxy::evict_after(proxy.clone(), "blue".to_string(), Duration::from_millis(10), generation).await;
+        assert!(is_evicted(&proxy, "blue").await);
+        assert!(!is_evicted(&proxy, "green").await);
+    }
+
+    #[tokio::test]
+    async fn switching_again_cancels_the_eviction() {
+        let proxy = proxy("blue").await;
+        let generation = {
+            let mut guard = proxy.lock().await;
+            guard.reroute_to(route("green"));
+            guard.generation
+        };
+        proxy.lock().await.reroute_to(route("blue"));
+
+        Proxy::evict_after(proxy.clone(), "blue".to_string(), Duration::from_millis(10), generation).await;
+        assert!(!is_evicted(&proxy, "blue").await);
+    }
+
+    #[tokio::test]
+    async fn switching_back_spares_connections_evicted_earlier() {
+        let proxy = proxy("blue").await;
+        let generation = {
+            let mut guard = proxy.lock().await;
+            guard.reroute_to(route("green"));
+            guard.generation
+        };
+        Proxy::evict_after(proxy.clone(), "blue".to_string(), Duration::from_millis(10), generation).await;
+        assert!(is_evicted(&proxy, "blue").await);
+
+        proxy.lock().await.reroute_to(route("blue"));
+        assert!(!is_evicted(&proxy, "blue").await);
+    }
 }
@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dialoguer::console::style;
+
+use crate::App;
+use crate::registration::types::{Service, SANDBOX_DIRECTIVES};
+use crate::settings::settings;
+use crate::supervisor::SupervisorKind;
+use crate::validate;
+
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn pass(message: String) {
+    println!("    {} {}", style("✓").green(), message);
+}
+
+pub(crate) fn fail(message: String) {
+    FAILED.fetch_add(1, Ordering::Relaxed);
+    println!("    {} {}", style("✗").red(), message);
+}
+
+/// How to fix the failure printed before it.
+pub(crate) fn fix(message: String) {
+    println!("      {} {}", style("fix:").dim(), message);
+}
+
+/// How many checks have failed so far.
+pub(crate) fn failures() -> usize {
+    FAILED.load(Ordering::Relaxed)
+}
+
+/// `Key=Value` lines of a unit file, ignoring sections and comments.
+fn unit_directives(unit: &str) -> Vec<(&str, &str)> {
+    unit.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('#') && !line.starts_with('['))
+        .filter_map(|line| line.split_once('='))
+        .collect()
+}
+
+pub(crate) fn check_hardening(app: &App, service: &Service) {
+    println!("  {}", style(&service.qualified_name).bold());
+
+    if app.supervisor == SupervisorKind::Builtin {
+        match &app.systemd.user {
+            Some(user) if user != "root" => pass(format!("runs as {}", user)),
+            None if settings().user_mode => pass("runs as whoever runs dorc".to_string()),
+            _ => fail("runs as root, set `user` under [systemd]".to_string()),
+        }
+        return fail("run by the built-in supervisor, which can't sandbox it".to_string());
+    }
+
+    let unit = match std::fs::read_to_string(service.unit_path()) {
+        Ok(unit) => unit,
+        Err(e) => return fail(format!("could not read {}: {}", service.unit_path(), e)),
+    };
+    let directives = unit_directives(&unit);
+    let has = |key: &str, value: &str| directives.iter().any(|(k, v)| *k == key && *v == value);
+
+    match directives.iter().find(|(k, _)| *k == "User") {
+        None if settings().user_mode => pass("runs as whoever runs dorc".to_string()),
+        Some((_, "root")) | None => fail("runs as root, set `user` or register with a sandboxed user".to_string()),
+        Some((_, user)) => pass(format!("runs as {}", user)),
+    }
+
+    for directive in SANDBOX_DIRECTIVES {
+        let (key, value) = directive.split_once('=').unwrap();
+        if has(key, value) {
+            pass(directive.to_string());
+        } else {
+            fail(format!("missing {}, set `sandbox = true` under [systemd]", directive));
+        }
+    }
+
+    if has("ReadWritePaths", &service.working_dir) {
+        pass(format!("ReadWritePaths={}", service.working_dir));
+    } else {
+        fail(format!("working dir {} isn't the only writable path", service.working_dir));
+    }
+}
+
+pub fn doctor() {
+    validate::check_everything(true);
+}
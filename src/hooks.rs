@@ -0,0 +1,128 @@
+use std::fmt;
+
+use anyhow::*;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::App;
+use crate::registration::types::Service;
+use crate::supervisor::builtin;
+
+/// Shell commands run around deployments, in the order they're listed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hooks {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) pre_migrate: Vec<String>, // before a release is copied to a slot, e.g. database migrations
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) post_migrate: Vec<String>, // once the slot runs the new release
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) pre_switch: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) post_switch: Vec<String>, // e.g. purging caches
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Hook {
+    PreMigrate,
+    PostMigrate,
+    PreSwitch,
+    PostSwitch,
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hook::PreMigrate => write!(f, "pre_migrate"),
+            Hook::PostMigrate => write!(f, "post_migrate"),
+            Hook::PreSwitch => write!(f, "pre_switch"),
+            Hook::PostSwitch => write!(f, "post_switch"),
+        }
+    }
+}
+
+impl Hooks {
+    fn commands(&self, hook: Hook) -> &[String] {
+        match hook {
+            Hook::PreMigrate => &self.pre_migrate,
+            Hook::PostMigrate => &self.post_migrate,
+            Hook::PreSwitch => &self.pre_switch,
+            Hook::PostSwitch => &self.post_switch,
+        }
+    }
+}
+
+/// Runs `hook`'s commands as `service` would run, logging their output. Stops at the first that fails.
+pub(crate) fn run(app: &App, service: &Service, hook: Hook) -> Result<()> {
+    let commands = app.hooks.commands(hook);
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    // before the first release there's no working dir yet
+    std::fs::create_dir_all(&service.working_dir)?;
+
+    for line in commands {
+        info!("Running {} hook for {}: {}", hook, service.qualified_name, line);
+        let output = builtin::command(app, service, line)?
+            .env("DORC_HOOK", hook.to_string())
+            .env("DORC_RELEASE_DIR", &app.release_dir)
+            .output()
+            .with_context(|| format!("could not run {} hook `{}`", hook, line))?;
+
+        for out in String::from_utf8_lossy(&output.stdout).lines().chain(String::from_utf8_lossy(&output.stderr).lines()) {
+            info!("[{} {}] {}", hook, service.qualified_name, out);
+        }
+
+        if !output.status.success() {
+            bail!("{} hook `{}` failed with {}", hook, line, output.status);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An app with `hooks`, and its only slot's working dir.
+    fn app(test: &str, hooks: &str) -> (App, std::path::PathBuf) {
+        let working_dir = std::env::temp_dir().join(format!("dorc-hooks-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&working_dir);
+        let app = toml::from_str(&format!(
+            "app_name = \"app\"\nrelease_dir = \"/srv/app\"\nrelease_bin = \"app\"\nactive = \"blue\"\n\
+            [[slots]]\nqualified_name = \"blue-app\"\nworking_dir = \"{}\"\nport = 9000\non_start = \"app\"\n\
+            [hooks]\n{}",
+            working_dir.display(), hooks
+        )).unwrap();
+        (app, working_dir)
+    }
+
+    fn ran(working_dir: &std::path::Path) -> String {
+        let ran = std::fs::read_to_string(working_dir.join("ran")).unwrap_or_default();
+        std::fs::remove_dir_all(working_dir).unwrap();
+        ran
+    }
+
+    #[test]
+    fn runs_commands_in_order_in_the_working_dir() {
+        let (app, working_dir) = app("order", r#"pre_switch = ["echo one >> ran", "echo $DORC_HOOK $DORC_COLOR >> ran"]"#);
+        run(&app, &app.slots[0], Hook::PreSwitch).unwrap();
+        assert_eq!(ran(&working_dir), "one\npre_switch blue\n");
+    }
+
+    #[test]
+    fn stops_at_the_first_failure() {
+        let (app, working_dir) = app("failure", r#"post_migrate = ["echo one >> ran", "exit 3", "echo two >> ran"]"#);
+        let error = run(&app, &app.slots[0], Hook::PostMigrate).unwrap_err().to_string();
+        assert!(error.contains("post_migrate hook `exit 3` failed"), "{}", error);
+        assert_eq!(ran(&working_dir), "one\n");
+    }
+
+    #[test]
+    fn only_runs_the_hook_asked_for() {
+        let (app, working_dir) = app("other", r#"pre_migrate = ["echo migrated >> ran"]"#);
+        run(&app, &app.slots[0], Hook::PostSwitch).unwrap();
+        assert!(!working_dir.exists());
+    }
+}
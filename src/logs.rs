@@ -0,0 +1,332 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::TimeZone;
+use dialoguer::console::style;
+
+use crate::App;
+use crate::registration::slot_color;
+use crate::registration::types::Service;
+use crate::settings::settings;
+use crate::supervisor::SupervisorKind;
+use crate::supervisor::builtin::{log_path, parse_log_line};
+
+// how many lines of each service's output `--follow` starts with, like journalctl
+const FOLLOW_BACKLOG: usize = 10;
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Where one instance's lines come from, and what they're prefixed with.
+struct Source {
+    label: String,
+    color: String,
+    instance: Service,
+}
+
+impl Source {
+    fn prefix(&self, width: usize) -> String {
+        format!("{} |", style(format!("{:<width$}", self.label, width = width)).fg(slot_color(&self.color)).bold())
+    }
+}
+
+/// The slots `--color` picks: one by name, `active`, `inactive` (every other slot), or all of them.
+fn chosen_slots<'a>(app: &'a App, color: Option<&str>) -> Result<Vec<&'a Service>> {
+    Ok(match color {
+        None => app.slots.iter().collect(),
+        Some("active") => vec![app.active_service()],
+        Some("inactive") => app.slots.iter().filter(|s| s.color() != app.active).collect(),
+        Some(name) => match app.slot(name) {
+            Some(slot) => vec![slot],
+            None => bail!(
+                "{} has no slot `{}`, try one of {}, active or inactive",
+                app.app_name, name,
+                app.slots.iter().map(|s| s.color()).collect::<Vec<_>>().join(", ")
+            ),
+        },
+    })
+}
+
+fn sources(app: &App, color: Option<&str>) -> Result<Vec<Source>> {
+    let mut sources = vec![];
+    for slot in chosen_slots(app, color)? {
+        for instance in slot.instances() {
+            let label = if slot.instances > 1 {
+                format!("{}@{}", slot.color(), instance.port)
+            } else {
+                slot.color().to_string()
+            };
+            sources.push(Source { label, color: slot.color().to_string(), instance });
+        }
+    }
+    Ok(sources)
+}
+
+pub(crate) fn logs(app_name: String, color: Option<String>, follow: bool, since: Option<String>) {
+    if let Err(e) = show(&app_name, color.as_deref(), follow, since.as_deref()) {
+        eprintln!("Failed to show {}'s logs: {}", app_name, e);
+    }
+}
+
+fn show(app_name: &str, color: Option<&str>, follow: bool, since: Option<&str>) -> Result<()> {
+    let app = App::load(settings().app_path(app_name))?;
+    let sources = sources(&app, color)?;
+    let width = sources.iter().map(|s| s.label.len()).max().unwrap_or(0);
+
+    match app.supervisor {
+        SupervisorKind::Systemd => journal(&sources, width, follow, since),
+        SupervisorKind::Builtin if since.is_some() => {
+            bail!("--since needs journald, the built-in supervisor's logs aren't timestamped")
+        }
+        SupervisorKind::Builtin if follow => tail(&sources, width),
+        SupervisorKind::Builtin => {
+            let logs = sources.iter()
+                .map(|source| read_log(&log_path(&source.instance.qualified_name)))
+                .collect::<Result<Vec<_>>>()?;
+            for (i, time, line) in merge(logs) {
+                print_line(time, &sources[i], width, &line);
+            }
+            Ok(())
+        }
+    }
+}
+
+type Time = chrono::DateTime<chrono::FixedOffset>;
+
+fn read_log(path: &str) -> Result<Vec<(Option<Time>, String)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let (time, output) = parse_log_line(&line);
+        lines.push((time, output.to_string()));
+    }
+    Ok(lines)
+}
+
+/// Interleaves each source's lines in the order they were written, tagged with their source's index.
+/// Lines without a time stay after the line before them.
+fn merge(logs: Vec<Vec<(Option<Time>, String)>>) -> Vec<(usize, Option<Time>, String)> {
+    let mut merged = vec![];
+    for (i, lines) in logs.into_iter().enumerate() {
+        let mut last = None;
+        for (time, line) in lines {
+            last = time.or(last);
+            merged.push((i, last, line));
+        }
+    }
+    // stable, so each source's lines keep their order
+    merged.sort_by_key(|(_, time, _)| *time);
+    merged
+}
+
+fn print_line<Tz: TimeZone>(time: Option<chrono::DateTime<Tz>>, source: &Source, width: usize, line: &str) {
+    let time = time
+        .map(|t| t.with_timezone(&chrono::Local).format("%b %d %H:%M:%S").to_string())
+        .unwrap_or_default();
+    // the width of a time, so lines from before dorc timestamped them still line up
+    println!("{} {} {}", style(format!("{:<15}", time)).dim(), source.prefix(width), line);
+}
+
+/// One journalctl for every unit, so journald interleaves their entries in order.
+fn journal(sources: &[Source], width: usize, follow: bool, since: Option<&str>) -> Result<()> {
+    let user_mode = settings().user_mode;
+    let mut journalctl = journalctl(sources, follow, since, user_mode);
+
+    let mut child = journalctl.stdout(Stdio::piped()).spawn()
+        .map_err(|e| anyhow!("could not run journalctl: {}", e))?;
+    let stdout = child.stdout.take().unwrap();
+
+    for line in BufReader::new(stdout).lines() {
+        let entry: serde_json::Value = serde_json::from_str(&line?)?;
+        let unit = unit(&entry, user_mode);
+        let source = match sources.iter().find(|s| unit == format!("{}.service", s.instance.qualified_name)) {
+            Some(source) => source,
+            None => continue,
+        };
+
+        let time = entry["__REALTIME_TIMESTAMP"].as_str()
+            .and_then(|us| us.parse::<i64>().ok())
+            .map(|us| chrono::Local.timestamp(us / 1_000_000, (us % 1_000_000) as u32 * 1000));
+
+        print_line(time, source, width, &message(&entry["MESSAGE"]));
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("journalctl exited with {}", status);
+    }
+    Ok(())
+}
+
+/// In user mode the units are the user's manager's, which journald files under different fields.
+fn journalctl(sources: &[Source], follow: bool, since: Option<&str>, user_mode: bool) -> Command {
+    let mut journalctl = Command::new("journalctl");
+    journalctl.args(["--output", "json", "--no-pager"]);
+    for source in sources {
+        let unit = format!("{}.service", source.instance.qualified_name);
+        journalctl.args([if user_mode { "--user-unit" } else { "--unit" }, &unit]);
+    }
+    if let Some(since) = since {
+        journalctl.args(["--since", since]);
+    }
+    if follow {
+        journalctl.arg("--follow");
+    }
+    journalctl
+}
+
+/// The unit an entry is about: the service's own output, or its manager's messages about it.
+fn unit(entry: &serde_json::Value, user_mode: bool) -> &str {
+    let (output, message) = if user_mode { ("_SYSTEMD_USER_UNIT", "USER_UNIT") } else { ("_SYSTEMD_UNIT", "UNIT") };
+    entry[output].as_str()
+        .filter(|u| !u.ends_with(".scope"))
+        .or_else(|| entry[message].as_str())
+        .unwrap_or_default()
+}
+
+/// journald hands over messages that aren't valid UTF-8 as arrays of bytes.
+fn message(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(message) => message.clone(),
+        serde_json::Value::Array(bytes) => {
+            let bytes: Vec<u8> = bytes.iter().filter_map(|b| b.as_u64()).map(|b| b as u8).collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+        _ => String::new(),
+    }
+}
+
+/// Follows the built-in supervisor's log files, printing lines as they're written.
+fn tail(sources: &[Source], width: usize) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+
+    for (i, source) in sources.iter().enumerate() {
+        let path = log_path(&source.instance.qualified_name);
+        let tx = tx.clone();
+        std::thread::spawn(move || -> Result<()> {
+            // the service may not have written anything yet
+            let file = loop {
+                match File::open(&path) {
+                    Ok(file) => break file,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::thread::sleep(POLL_INTERVAL),
+                    Err(e) => return Err(e.into()),
+                }
+            };
+
+            let mut reader = BufReader::new(file);
+            let backlog: Vec<String> = (&mut reader).lines().collect::<std::io::Result<_>>()?;
+            for line in backlog.iter().skip(backlog.len().saturating_sub(FOLLOW_BACKLOG)) {
+                tx.send((i, line.clone()))?;
+            }
+
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let position = reader.stream_position()?;
+                if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+                    // wait for the rest of a partly written line
+                    reader.seek(SeekFrom::Start(position))?;
+                    std::thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                tx.send((i, line.trim_end_matches('\n').to_string()))?;
+            }
+        });
+    }
+    drop(tx);
+
+    for (i, line) in rx {
+        let (time, line) = parse_log_line(&line);
+        print_line(time, &sources[i], width, line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<(Option<Time>, String)> {
+        lines.iter().map(|line| {
+            let (time, output) = parse_log_line(line);
+            (time, output.to_string())
+        }).collect()
+    }
+
+    #[test]
+    fn parses_timestamped_lines() {
+        let (time, output) = parse_log_line("2021-10-24T15:30:12.000001+00:00 listening on :9100");
+        assert_eq!(time.unwrap().timestamp_subsec_micros(), 1);
+        assert_eq!(output, "listening on :9100");
+
+        assert_eq!(parse_log_line("listening on :9100"), (None, "listening on :9100"));
+        assert_eq!(parse_log_line(""), (None, ""));
+    }
+
+    #[test]
+    fn merges_by_time() {
+        let blue = lines(&[
+            "2021-10-24T15:30:01+00:00 blue 1",
+            "2021-10-24T15:30:03+00:00 blue 3",
+        ]);
+        let green = lines(&[
+            "2021-10-24T15:30:02+00:00 green 2",
+            "2021-10-24T17:30:04+02:00 green 4",
+        ]);
+
+        let merged: Vec<_> = merge(vec![blue, green]).into_iter().map(|(i, _, line)| (i, line)).collect();
+        assert_eq!(merged, vec![
+            (0, "blue 1".to_string()),
+            (1, "green 2".to_string()),
+            (0, "blue 3".to_string()),
+            (1, "green 4".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn untimestamped_lines_follow_the_line_before() {
+        let blue = lines(&[
+            "written before timestamps",
+            "2021-10-24T15:30:01+00:00 blue 1",
+            "2021-10-24T15:30:03+00:00 blue 3",
+            "continued",
+        ]);
+        let green = lines(&["2021-10-24T15:30:02+00:00 green 2"]);
+
+        let merged: Vec<_> = merge(vec![blue, green]).into_iter().map(|(_, _, line)| line).collect();
+        assert_eq!(merged, vec!["written before timestamps", "blue 1", "green 2", "blue 3", "continued"]);
+    }
+
+    #[test]
+    fn user_mode_reads_the_users_units() {
+        let app: App = toml::from_str(r#"
+            app_name = "app"
+            release_dir = "/srv/app"
+            release_bin = "app"
+            active = "blue"
+            [[slots]]
+            qualified_name = "blue-app"
+            working_dir = "/srv/blue-app"
+            port = 9000
+            on_start = "app"
+        "#).unwrap();
+        let sources = sources(&app, None).unwrap();
+        let args = |user_mode| journalctl(&sources, false, None, user_mode).get_args()
+            .map(|a| a.to_string_lossy().into_owned()).collect::<Vec<_>>().join(" ");
+        assert_eq!(args(false), "--output json --no-pager --unit blue-app.service");
+        assert_eq!(args(true), "--output json --no-pager --user-unit blue-app.service");
+
+        let output = serde_json::json!({"_SYSTEMD_USER_UNIT": "blue-app.service", "_SYSTEMD_UNIT": "user@1000.service"});
+        assert_eq!(unit(&output, true), "blue-app.service");
+        let stopped = serde_json::json!({"_SYSTEMD_USER_UNIT": "init.scope", "USER_UNIT": "blue-app.service"});
+        assert_eq!(unit(&stopped, true), "blue-app.service");
+        assert_eq!(unit(&serde_json::json!({"_SYSTEMD_UNIT": "blue-app.service"}), false), "blue-app.service");
+    }
+}
@@ -1,23 +1,47 @@
-#![feature(async_closure)]
-
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::fs::create_dir_all;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
 
-use anyhow::Result;
-use fs_extra::dir::CopyOptions;
+use anyhow::{anyhow, bail, Result};
+use chrono::TimeZone;
+use log::{info, warn};
 use serde::Serialize;
 use serde_derive::*;
 use structopt::StructOpt;
 
-use registration::types::Service;
-use std::process::Command;
+use registration::types::{Service, SystemdConfig};
 
-use crate::daemon::FIFO;
+use crate::daemon::access::AccessConfig;
+use crate::daemon::access_log::AccessLogConfig;
+use crate::daemon::balance::{HealthConfig, Strategy};
+use crate::daemon::mirror::MirrorConfig;
+use crate::hooks::{Hook, Hooks};
+use crate::ports::PortAllocator;
+use crate::supervisor::{Status, Supervisor, SupervisorKind};
+use crate::settings::{settings, LogFormat, Settings};
+use crate::sync::SyncConfig;
 
 
 mod registration;
 mod daemon;
+mod doctor;
+mod hooks;
+mod logs;
+mod migrations;
+mod ports;
+mod secrets;
+mod settings;
+mod supervisor;
+mod sync;
+mod templates;
+mod validate;
+
+// where binaries were copied before they were run from the working dir
+const LEGACY_BIN_DIR: &str = "/usr/local/bin";
 
 // const SERVICE_FILE_PATH: &str = "/usr/lib/systemd/system/dorc.service";
 
@@ -27,6 +51,15 @@ mod daemon;
     about = "devin's orchestrator - a stupid deployment utility"
 )]
 struct Opt {
+    /// dorc's own settings (defaults to $DORC_CONFIG, then /etc/dorc/dorc.toml)
+    #[structopt(long, global = true, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Keep dorc's files under this dir instead of / (defaults to $DORC_ROOT)
+    #[structopt(long, global = true)]
+    root: Option<String>,
+    /// Run services as yourself, through `systemctl --user` or the built-in supervisor (defaults to $DORC_USER_MODE)
+    #[structopt(long = "user", global = true)]
+    user_mode: bool,
     #[structopt(subcommand)]
     subcommand: Subcommands,
 }
@@ -34,122 +67,771 @@ struct Opt {
 #[derive(Debug, PartialEq, StructOpt)]
 enum Subcommands {
     Register,
-    StartDaemon,
+    StartDaemon {
+        /// Take over the listeners of an already running daemon
+        #[structopt(long)]
+        takeover: bool,
+    },
     Load { name: String },
-    Switch { name: String },
+    Switch {
+        name: String,
+        /// The slot to route to, e.g. `blue` (defaults to the one with the newest release)
+        #[structopt(long)]
+        to: Option<String>,
+        /// Close connections left on the old service after this long, e.g. `30s` (overrides the app's grace_period)
+        #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+        grace: Option<Duration>,
+    },
+    /// Log connection error rates per color, including mirrored traffic, to the daemon's log
+    Stats { name: String },
+    /// Show the output of an app's services, labelled by slot
+    Logs {
+        name: String,
+        /// A slot like `blue`, or `active` or `inactive` (defaults to every slot)
+        #[structopt(long)]
+        color: Option<String>,
+        /// Keep printing new output as it's written
+        #[structopt(short, long)]
+        follow: bool,
+        /// Only output since this time, e.g. `1h ago` or `2021-10-24 15:30` (passed to journalctl)
+        #[structopt(long)]
+        since: Option<String>,
+    },
+    /// List what copying the next release would delete from a slot's working dir, without copying it
+    DryRunSync {
+        name: String,
+        /// The slot to check (defaults to the one the next release goes to)
+        #[structopt(long)]
+        color: Option<String>,
+    },
+    /// Check apps and their services for common problems, including how well they're sandboxed
+    Doctor,
+    /// Check every app's config for port collisions, missing files, edited units and leftovers, exiting with 1 if any
+    Validate,
+    /// Set an environment variable that's kept out of the app's config, readable only by root
+    SetSecret {
+        name: String,
+        key: String,
+        /// Prompted for when left out
+        value: Option<String>,
+        /// Only for this color's service
+        #[structopt(long)]
+        color: Option<String>,
+    },
+    UnsetSecret {
+        name: String,
+        key: String,
+        #[structopt(long)]
+        color: Option<String>,
+    },
+    /// Replace the running daemon with the installed binary, without dropping connections
+    Upgrade,
+    /// Timestamps each line of stdin onto the end of `path`, for the built-in supervisor
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    WriteLog { path: String },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct App {
+    #[serde(default)]
+    version: u32, // see `migrations`
     app_name: String,
     release_dir: String,
     release_bin: String,
-    listen_port: u16,
+    #[serde(default)]
+    listen_port: u16, // 0 or left out is picked from `port_range` when the daemon loads the app
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    listen_address: Option<IpAddr>, // defaults to the bind_address in dorc.toml
+    #[serde(default)]
+    socket_activated: bool,
+    #[serde(default)]
+    supervisor: SupervisorKind,
+    // how long connections may stay on the old service after a switch
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    grace_period: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    environment_files: Vec<String>,
+    #[serde(default)]
+    active: String, // the slot traffic is routed to, e.g. `blue`
+    #[serde(default)]
+    balance: Strategy, // how connections are spread across a slot's instances
+    #[serde(default)]
+    health: HealthConfig,
+
+    #[serde(default)]
+    slots: Vec<Service>,
+
+    #[serde(default)]
+    systemd: SystemdConfig,
+    access_log: Option<AccessLogConfig>,
+    access: Option<AccessConfig>,
+    mirror: Option<MirrorConfig>,
+    #[serde(default)]
+    hooks: Hooks,
+    #[serde(default)]
+    sync: SyncConfig,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    environment: BTreeMap<String, String>, // shared by every slot
+
+    // slots whose commands ran a copy of the binary in LEGACY_BIN_DIR, see `migrate_legacy_bins`
+    #[serde(skip)]
+    legacy_bins: Vec<String>,
+    // the version and text of a config `load` upgraded, for `write_upgraded` to back up
+    #[serde(skip)]
+    upgraded_from: Option<(u32, String)>,
+}
 
-    active_service: Service,
-    inactive_service: Service
+/// Where a slot runs the release's binary from: the copy in its working dir,
+/// or one dorc keeps for it if the binary isn't part of the release dir.
+pub(crate) fn bin_path(release_dir: &str, release_bin: &str, working_dir: &str, slot_name: &str) -> String {
+    match Path::new(release_bin).strip_prefix(release_dir) {
+        Ok(relative) => Path::new(working_dir).join(relative).to_string_lossy().into_owned(),
+        Err(_) => format!("{}/{}", settings().bin_dir(), slot_name),
+    }
+}
+
+/// `command` with the legacy copy of the slot's binary swapped for `bin`,
+/// or `None` if it doesn't run that copy.
+fn unlegacy_command(command: &str, slot_name: &str, bin: &str) -> Option<String> {
+    let legacy_path = format!("{}/{}", LEGACY_BIN_DIR, slot_name);
+    let rest = command.strip_prefix(legacy_path.as_str()).or_else(|| command.strip_prefix(slot_name))?;
+    if rest.is_empty() || rest.starts_with(' ') {
+        Some(format!("{}{}", bin, rest))
+    } else {
+        None
+    }
 }
 
 impl App {
     pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<App> {
+        let path = path.as_ref();
         let toml = std::fs::read_to_string(path)?;
-        let result: App = toml::from_str(&toml)?;
+        let mut value: toml::Value = toml::from_str(&toml)
+            .map_err(|e| anyhow!("{} isn't valid TOML: {}", path.display(), e))?;
+        let written_as = migrations::migrate(&mut value).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let mut result: App = serde_path_to_error::deserialize(value).map_err(|e| match e.path().to_string().as_str() {
+            "." => anyhow!("{}: {}", path.display(), e.inner()),
+            field => anyhow!("{}: `{}`: {}", path.display(), field, e.inner()),
+        })?;
+
+        for i in 0..result.slots.len() {
+            let bin = result.bin_path(&result.slots[i]);
+            let slot = &mut result.slots[i];
+            let slot_name = slot.slot_name().to_string();
+
+            match unlegacy_command(&slot.on_start, &slot_name, &bin) {
+                Some(on_start) => slot.on_start = on_start,
+                None => continue,
+            }
+            for commands in slot.on_reload.iter_mut().chain(slot.on_stop.iter_mut()) {
+                for command in commands.iter_mut() {
+                    if let Some(unlegacied) = unlegacy_command(command, &slot_name, &bin) {
+                        *command = unlegacied;
+                    }
+                }
+            }
+            // the old default, which won't find the binary by its new name; stopping the main process does the same
+            if let Some(on_stop) = &mut slot.on_stop {
+                on_stop.retain(|command| *command != format!("killall {}", slot_name));
+            }
+            if slot.on_stop.as_ref().is_some_and(Vec::is_empty) {
+                slot.on_stop = None;
+            }
+            result.legacy_bins.push(slot_name);
+        }
+
+        if result.slots.is_empty() {
+            bail!("{} has no slots", result.app_name);
+        }
+        if result.slot(&result.active).is_none() {
+            bail!("{}'s active slot `{}` doesn't exist", result.app_name, result.active);
+        }
+
+        if let Some(access) = &result.access {
+            access.validate().map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        }
+        if let Some(mirror) = &result.mirror {
+            mirror.validate().map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        }
+
+        for slot in &result.slots {
+            if slot.last_port().is_none() {
+                bail!("{}: {}'s {} instances starting at port {} don't fit below 65536", path.display(), slot.color(), slot.instances, slot.port);
+            }
+        }
+
+        if written_as < migrations::CURRENT_VERSION {
+            // only the daemon writes the upgrade, once it's moved any legacy binaries
+            result.upgraded_from = Some((written_as, toml));
+        }
+
         Ok(result)
     }
 
+    /// Picks ports for a config that leaves some out. Returns whether there were any to pick.
+    /// Only the daemon does this, so other commands never write to apps.
+    pub(crate) fn allocate_ports(&mut self) -> Result<bool> {
+        if self.listen_port != 0 && self.slots.iter().all(|slot| slot.port != 0) {
+            return Ok(false);
+        }
+
+        let mut allocator = PortAllocator::new(Some(&self.app_name));
+        // its own ports that are set
+        if self.listen_port != 0 {
+            allocator.reserve(self.listen_port, 1);
+        }
+        for slot in self.slots.iter().filter(|slot| slot.port != 0) {
+            allocator.reserve(slot.port, slot.instances);
+        }
+
+        if self.listen_port == 0 {
+            self.listen_port = allocator.allocate(1)?;
+            info!("Picked port {} for {} to listen on", self.listen_port, self.app_name);
+        }
+        for slot in self.slots.iter_mut().filter(|slot| slot.port == 0) {
+            slot.port = allocator.allocate(slot.instances)?;
+            info!("Picked port {} for {}", slot.port, slot.qualified_name);
+        }
+        Ok(true)
+    }
+
+    /// Records the ports `allocate_ports` picked, so they stay the same. Warns if it can't, like `write_upgraded`.
+    pub(crate) fn write_allocated(&self, path: &Path) {
+        if let Err(e) = std::fs::write(path, toml::to_string(self).unwrap()) {
+            warn!("Could not save the ports picked for {}, they'll be picked again next time | {}", path.display(), e);
+        }
+    }
+
+    /// Replaces a config `load` upgraded, backing the original up outside the apps dir,
+    /// where it can't be mistaken for an app. Failing to is only a warning, the upgrade is redone next time.
+    pub(crate) fn write_upgraded(&self, path: &Path) {
+        let (written_as, original) = match &self.upgraded_from {
+            Some(upgraded_from) => upgraded_from,
+            None => return,
+        };
+        let backup_dir = format!("{}/backups", settings().state_dir);
+        let backup = format!("{}/{}.v{}.bak", backup_dir, path.file_name().unwrap_or_default().to_string_lossy(), written_as);
+        let result = create_dir_all(&backup_dir)
+            .and_then(|_| std::fs::write(&backup, original))
+            .and_then(|_| std::fs::write(path, toml::to_string(self).unwrap()));
+
+        match result {
+            Ok(_) => info!("Upgraded {} from config version {}, the original is in {}", path.display(), written_as, backup),
+            Err(e) => warn!("Could not upgrade {} from config version {} | {}", path.display(), written_as, e),
+        }
+    }
+
+    pub(crate) fn slot(&self, name: &str) -> Option<&Service> {
+        self.slots.iter().find(|s| s.color() == name)
+    }
+
+    pub(crate) fn active_service(&self) -> &Service {
+        self.slot(&self.active).expect("active slot exists")
+    }
+
+    /// The slot holding the newest release that isn't live yet, where `dorc switch` goes by default.
+    pub(crate) fn standby_service(&self) -> Option<&Service> {
+        self.slots.iter()
+            .filter(|s| s.color() != self.active)
+            .max_by(|a, b| a.release_id.cmp(&b.release_id))
+    }
+
+    /// Where the next release goes: the slot with the oldest release that isn't live.
+    fn ingest_slot(&self) -> Option<usize> {
+        self.slots.iter()
+            .enumerate()
+            .filter(|(_, s)| s.color() != self.active)
+            .min_by(|(_, a), (_, b)| a.release_id.cmp(&b.release_id))
+            .map(|(i, _)| i)
+    }
+
+    /// The slot a switch goes to: `slot`, or the standby slot if none is given.
+    fn switch_target(&self, slot: Option<&str>) -> Result<String> {
+        let target = match slot {
+            Some(slot) if self.slot(slot).is_none() => bail!("{} has no slot `{}`", self.app_name, slot),
+            Some(slot) => slot.to_string(),
+            None => match self.standby_service() {
+                Some(service) => service.color().to_string(),
+                None => bail!("{} has no other slot to switch to", self.app_name),
+            },
+        };
+
+        if target == self.active {
+            bail!("{} is already active for {}", target, self.app_name);
+        }
+
+        Ok(target)
+    }
+
     pub(crate) fn save(&self) {
         let toml = toml::to_string(&self).unwrap();
-        create_dir_all("/etc/dorc/apps").expect("Could not create /etc/dorc/apps/");
-        std::fs::write(format!("/etc/dorc/apps/{}.toml", self.app_name), toml)
+        create_dir_all(&settings().apps_dir).expect("Could not create the apps dir");
+        std::fs::write(settings().app_path(&self.app_name), toml)
             .expect("Could not write to toml file");
     }
 
+    pub(crate) fn listen_address(&self) -> SocketAddr {
+        SocketAddr::new(self.listen_address.unwrap_or(settings().bind_address), self.listen_port)
+    }
+
+    pub(crate) fn supervisor(&self) -> Box<dyn Supervisor> {
+        self.supervisor.supervisor()
+    }
+
+    pub(crate) fn bin_path(&self, service: &Service) -> String {
+        bin_path(&self.release_dir, &self.release_bin, &service.working_dir, service.slot_name())
+    }
+
+    fn bin_outside_release(&self) -> bool {
+        !Path::new(&self.release_bin).starts_with(&self.release_dir)
+    }
+
+    /// Moves binaries out of LEGACY_BIN_DIR, for slots `load` pointed at their new place.
+    /// Returns whether there was anything to move, and so whether the app needs saving.
+    pub(crate) fn migrate_legacy_bins(&self) -> Result<bool> {
+        for slot_name in &self.legacy_bins {
+            let legacy_path = format!("{}/{}", LEGACY_BIN_DIR, slot_name);
+            if !Path::new(&legacy_path).exists() {
+                continue;
+            }
+
+            // binaries in the release dir were copied to the working dir with it
+            if self.bin_outside_release() {
+                create_dir_all(settings().bin_dir())?;
+                std::fs::copy(&legacy_path, format!("{}/{}", settings().bin_dir(), slot_name))?;
+            }
+            std::fs::remove_file(&legacy_path)?;
+            info!("Moved {}'s binary out of {}", slot_name, LEGACY_BIN_DIR);
+        }
+
+        Ok(!self.legacy_bins.is_empty())
+    }
+
+    /// Every instance of every slot.
+    pub(crate) fn instances(&self) -> Vec<Service> {
+        self.slots.iter().flat_map(|slot| slot.instances()).collect()
+    }
+
     fn migrate_service(&self, service: &Service) -> Result<()>{
-        std::process::Command::new("systemctl")
-            .args(&["stop", &service.qualified_name])
-            .output()?;
+        hooks::run(self, service, Hook::PreMigrate)?;
 
-        // Don't clear working directory here,
+        let supervisor = self.supervisor();
+        let instances = service.instances();
+        for instance in &instances {
+            supervisor.stop(self, instance)?;
+        }
+
+        // only a mirror sync clears out the working dir,
         // users of dorc may want to store data in files that are subservice specific
+        self.sync.prune(&self.release_dir, &service.working_dir)?;
 
-        fs_extra::dir::copy(
-            &self.release_dir,
-            &service.working_dir,
-            &CopyOptions {
-                overwrite: true,
-                skip_exist: false,
-                buffer_size: 64000,
-                copy_inside: true,
-                content_only: true,
-                depth: 0,
-            },
-        )?;
-
-        std::fs::copy(
-            self.release_bin.as_str(),
-            format!("/usr/local/bin/{}", &service.qualified_name),
-        )?;
-
-        let sysdservice = service.to_systemd_service();
-        std::fs::write(
-            format!("/etc/systemd/system/{}.service", service.qualified_name),
-            sysdservice.to_string(),
-        )?;
-
-        std::process::Command::new("systemctl")
-            .args(&["start", &service.qualified_name])
-            .output()?;
-        std::process::Command::new("systemctl")
-            .args(&["enable", &service.qualified_name])
-            .output()?;
+        let stats = self.sync.copy(&self.app_name, &self.release_dir, &service.working_dir)?;
+        info!("synced {} to {}: {}", self.release_dir, service.working_dir, stats);
+        let rendered = templates::render_all(self, service)?;
+        if rendered > 0 {
+            info!("rendered {} templates into {}", rendered, service.working_dir);
+        }
+
+        // otherwise it was copied along with the rest of the release
+        if self.bin_outside_release() {
+            create_dir_all(settings().bin_dir())?;
+            std::fs::copy(&self.release_bin, self.bin_path(service))?;
+        }
+
+        // releases are copied as root, hand them to the service's user
+        if let Some(user) = &self.systemd.user {
+            let owner = match &self.systemd.group {
+                Some(group) => format!("{}:{}", user, group),
+                None => user.clone(),
+            };
+            let status = std::process::Command::new("chown")
+                .args(["-R", &owner, &service.working_dir])
+                .status()?;
+            if !status.success() {
+                anyhow::bail!("failed to chown {} to {}", service.working_dir, owner);
+            }
+        }
+
+        for instance in &instances {
+            supervisor.install(self, instance)?;
+            supervisor.start(self, instance)?;
+            supervisor.enable(instance)?;
+        }
+
+        hooks::run(self, service, Hook::PostMigrate)?;
 
         // TODO: undo changes on failure? and/or fail early?
 
         Ok(())
     }
 
-    fn swap_active(&mut self) {
-        std::mem::swap(&mut self.inactive_service, &mut self.active_service);
+    /// Rewrites any service definition that differs from what this config generates,
+    /// and restarts the standby services whose definitions changed. The active slot is still taking traffic,
+    /// so it keeps running as it is until it's next started, e.g. when a release is copied to it after a switch.
+    /// Services of `previous` that would keep a new instance from binding its port are removed first.
+    pub(crate) fn sync_units(&self, previous: Option<&App>) -> Result<()> {
+        let mut failures = match previous {
+            Some(previous) => previous.remove_units(&self.stale_units(previous).0),
+            None => vec![],
+        };
+
+        let supervisor = self.supervisor();
+        failures.extend(each_service(&self.instances(), |service| {
+            if !supervisor.install(self, service)? {
+                return Ok(());
+            }
+            info!("Regenerated the unit for {}", service.qualified_name);
+
+            if service.color() == self.active && supervisor.status(service)? != Status::Active {
+                // e.g. a new instance, after `instances` went up
+                supervisor.start(self, service)?;
+                supervisor.enable(service)?;
+            } else if service.color() == self.active {
+                info!("{} keeps running with its old definition until it's next started", service.qualified_name);
+            } else if supervisor.status(service)? == Status::Active {
+                supervisor.stop(self, service)?;
+                supervisor.start(self, service)?;
+            }
+            Ok(())
+        }));
+
+        failed(failures)
+    }
+
+    /// The services `previous` had that this config doesn't, e.g. after changing `instances` renamed them:
+    /// those on a port one of ours uses, which have to go before ours can start, and the rest.
+    fn stale_units(&self, previous: &App) -> (Vec<Service>, Vec<Service>) {
+        let current = self.instances();
+        previous.instances().into_iter()
+            .filter(|s| !current.iter().any(|c| c.qualified_name == s.qualified_name))
+            .partition(|s| current.iter().any(|c| c.port == s.port))
+    }
+
+    /// Stops and removes the rest of the services `previous` had that this config doesn't,
+    /// once traffic has moved to the services that replace them.
+    pub(crate) fn remove_stale_units(&self, previous: &App) -> Result<()> {
+        failed(previous.remove_units(&self.stale_units(previous).1))
+    }
+
+    /// Stops and removes `services`, carrying on past the ones that fail.
+    fn remove_units(&self, services: &[Service]) -> Vec<String> {
+        let supervisor = self.supervisor();
+        each_service(services, |service| {
+            supervisor.stop(self, service)?;
+            supervisor.remove(service)?;
+            info!("Removed {}", service.qualified_name);
+            Ok(())
+        })
+    }
+
+    pub(crate) fn socket_unit_name(&self) -> String {
+        format!("dorc-{}.socket", self.app_name)
+    }
+
+    /// A socket unit that holds the listen port on dorc's behalf, so it survives daemon restarts.
+    pub(crate) fn to_systemd_socket(&self) -> String {
+        format!(
+            "[Unit]\n\
+            Description=dorc listener for {name}\n\
+            \n\
+            [Socket]\n\
+            ListenStream={address}\n\
+            FileDescriptorName={name}\n\
+            Service=dorc.service\n\
+            \n\
+            [Install]\n\
+            WantedBy=sockets.target\n",
+            name = self.app_name,
+            address = self.listen_address(),
+        )
     }
 
 }
 
+/// Does `action` for each service, carrying on past failures, which are returned.
+fn each_service(services: &[Service], mut action: impl FnMut(&Service) -> Result<()>) -> Vec<String> {
+    services.iter()
+        .filter_map(|service| action(service).err().map(|e| format!("{}: {}", service.qualified_name, e)))
+        .collect()
+}
+
+fn failed(failures: Vec<String>) -> Result<()> {
+    match failures.is_empty() {
+        true => Ok(()),
+        false => bail!("{}", failures.join("; ")),
+    }
+}
+
+/// Identifies a copied release, e.g. `20211024153012345`. Each is later than the last,
+/// even within the same millisecond, so slots released together don't tie.
+pub(crate) fn new_release_id() -> String {
+    static LAST: AtomicI64 = AtomicI64::new(0);
+
+    let now = chrono::Local::now().timestamp_millis();
+    // never fails, the closure always returns Some
+    let last = LAST.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1))).unwrap();
+    let millis = now.max(last + 1);
+    chrono::Local.timestamp_millis_opt(millis).unwrap().format("%Y%m%d%H%M%S%3f").to_string()
+}
+
 #[tokio::main]
 async fn main() {
     let opt: Opt = Opt::from_args();
 
+    // runs for as long as its service does, it doesn't need settings
+    if let Subcommands::WriteLog{path} = &opt.subcommand {
+        if let Err(e) = supervisor::builtin::write_log(path) {
+            eprintln!("Could not write to {}: {}", path, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    match Settings::load(opt.config, opt.root, opt.user_mode) {
+        Ok(loaded) => settings::init(loaded),
+        Err(e) => {
+            eprintln!("Could not load dorc's settings: {}", e);
+            std::process::exit(1);
+        }
+    }
     configure_logging();
 
     match opt.subcommand {
-        Subcommands::StartDaemon => { daemon::start().await; }
+        Subcommands::StartDaemon{takeover} => { daemon::start(takeover).await; }
         Subcommands::Register => { registration::register(); }
-        Subcommands::Load{name} => {
-            // is this code smell?
-            // TODO: spawn an async thread and kill after 3 seconds.
-            Command::new("sh")
-            .arg("-c")
-            .arg(format!("echo 'load {}' > {}", name, FIFO))
-            .output()
-            .expect("failed to execute process");
-        }
-        Subcommands::Switch{name} => {
-            Command::new("sh")
-                .arg("-c")
-                .arg(format!("echo 'switch {}' > {}", name, FIFO))
-                .output()
-                .expect("failed to execute process");
+        Subcommands::Load{name} => tell_daemon(&format!("load {}", name)),
+        Subcommands::Switch{name, to, grace} => {
+            let to = to.map(|slot| format!(" to={}", slot)).unwrap_or_default();
+            // one token, `humantime::format_duration` would write `1m 30s`
+            let grace = grace.map(|g| format!(" {}ms", g.as_millis())).unwrap_or_default();
+            tell_daemon(&format!("switch {}{}{}", name, to, grace));
         }
+        Subcommands::Stats{name} => {
+            tell_daemon(&format!("stats {}", name));
+            println!("Stats for {} have been written to the daemon's log (journalctl -u dorc).", name);
+        }
+        Subcommands::Logs{name, color, follow, since} => logs::logs(name, color, follow, since),
+        Subcommands::DryRunSync{name, color} => sync::dry_run(name, color),
+        Subcommands::Doctor => doctor::doctor(),
+        Subcommands::Validate => validate::validate(),
+        Subcommands::SetSecret{name, key, value, color} => secrets::set_secret(name, key, value, color),
+        Subcommands::UnsetSecret{name, key, color} => secrets::unset_secret(name, key, color),
+        Subcommands::Upgrade => tell_daemon("upgrade"),
+        Subcommands::WriteLog{..} => unreachable!(),
+    }
+}
+
+/// Where our own executable is, even after a package upgrade has replaced it on disk.
+pub(crate) fn own_exe() -> std::io::Result<PathBuf> {
+    std::env::current_exe().map(|p| PathBuf::from(p.to_string_lossy().trim_end_matches(" (deleted)")))
+}
+
+/// Sends `command` to the daemon, exiting with 1 if it answers that it failed.
+fn tell_daemon(command: &str) {
+    if let Err(e) = daemon::admin::send(command) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
 
 fn configure_logging() {
+    let config = &settings().log;
     let mut fern = fern::Dispatch::new();
 
-    if cfg!(debug_assertions) {
-        fern = fern.level(log::LevelFilter::Debug);
-    } else {
-        fern = fern.level(log::LevelFilter::Info);
+    let level = config.level.as_ref().and_then(|level| match level.parse() {
+        Ok(level) => Some(level),
+        Err(_) => {
+            eprintln!("Unknown log level `{}`, try error, warn, info, debug or trace", level);
+            None
+        }
+    });
+    fern = match level {
+        Some(level) => fern.level(level),
+        None if cfg!(debug_assertions) => fern.level(log::LevelFilter::Debug),
+        None => fern.level(log::LevelFilter::Info),
+    };
+
+    fern = match config.format {
+        LogFormat::Plain => fern,
+        LogFormat::Timestamped => fern.format(|out, message, record| {
+            out.finish(format_args!(
+                "{} {:<5} {}: {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                record.level(),
+                record.target(),
+                message
+            ))
+        }),
+        LogFormat::Json => fern.format(|out, message, record| {
+            out.finish(format_args!("{}", serde_json::json!({
+                "time": chrono::Local::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": message.to_string(),
+            })))
+        }),
+    };
+
+    let destination = match &config.destination {
+        Some(path) => match fern::log_file(path) {
+            Ok(file) => fern::Output::from(file),
+            Err(e) => {
+                eprintln!("Could not open {} for logging, using stdout: {}", path, e);
+                fern::Output::from(std::io::stdout())
+            }
+        },
+        None => fern::Output::from(std::io::stdout()),
+    };
+
+    fern.chain(destination).apply().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_ids_never_tie() {
+        let ids: Vec<String> = (0..100).map(|_| new_release_id()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(ids[0].len(), "20211024153012345".len());
+    }
+
+    /// An app with a slot per `(color, release_id)`, `active` being live.
+    fn app(active: &str, slots: &[(&str, Option<&str>)]) -> App {
+        let slots: String = slots.iter().enumerate().map(|(i, (color, release_id))| format!(
+            "[[slots]]\nqualified_name = \"{color}-app\"\nworking_dir = \"/srv/{color}-app\"\nport = {}\non_start = \"app\"\n{}\n",
+            9000 + i,
+            release_id.map(|id| format!("release_id = \"{}\"", id)).unwrap_or_default(),
+            color = color,
+        )).collect();
+        toml::from_str(&format!(
+            "app_name = \"app\"\nrelease_dir = \"/srv/app\"\nrelease_bin = \"app\"\nactive = \"{}\"\n{}",
+            active, slots
+        )).unwrap()
     }
 
-    fern.chain(std::io::stdout()).apply().unwrap();
+    #[test]
+    fn releases_go_to_the_slot_with_the_oldest_one() {
+        let ingested = |app: App| app.ingest_slot().map(|i| app.slots[i].color().to_string());
+        assert_eq!(ingested(app("blue", &[("blue", Some("3")), ("green", Some("2")), ("red", Some("1"))])).as_deref(), Some("red"));
+        // never released to beats released to long ago
+        assert_eq!(ingested(app("blue", &[("blue", Some("3")), ("green", Some("1")), ("red", None)])).as_deref(), Some("red"));
+        assert_eq!(ingested(app("blue", &[("blue", None)])), None);
+    }
+
+    #[test]
+    fn switches_to_the_newest_release_unless_told_otherwise() {
+        let app = app("blue", &[("blue", Some("1")), ("green", Some("3")), ("red", Some("2"))]);
+        assert_eq!(app.switch_target(None).unwrap(), "green");
+        assert_eq!(app.switch_target(Some("red")).unwrap(), "red");
+    }
+
+    #[test]
+    fn wont_switch_to_the_active_slot_or_one_that_isnt_there() {
+        let two = app("blue", &[("blue", Some("1")), ("green", Some("2"))]);
+        assert!(two.switch_target(Some("blue")).is_err());
+        assert!(two.switch_target(Some("purple")).is_err());
+        assert!(app("blue", &[("blue", Some("1"))]).switch_target(None).is_err());
+    }
+
+    fn with_instances(instances: u16) -> App {
+        let mut app = app("blue", &[("blue", None)]);
+        app.slots[0].instances = instances;
+        app
+    }
+
+    fn names(services: &[Service]) -> Vec<&str> {
+        services.iter().map(|s| s.qualified_name.as_str()).collect()
+    }
+
+    #[test]
+    fn a_renamed_instance_frees_its_port_before_the_new_ones_start() {
+        // one instance is named after its slot, more after their ports too
+        let (in_the_way, rest) = with_instances(2).stale_units(&with_instances(1));
+        assert_eq!(names(&in_the_way), vec!["blue-app"]);
+        assert!(rest.is_empty());
+
+        let (in_the_way, rest) = with_instances(1).stale_units(&with_instances(2));
+        assert_eq!(names(&in_the_way), vec!["blue-app@9000"]);
+        assert_eq!(names(&rest), vec!["blue-app@9001"]);
+    }
+
+    #[test]
+    fn unchanged_instances_arent_stale() {
+        let (in_the_way, rest) = with_instances(3).stale_units(&with_instances(2));
+        assert!(in_the_way.is_empty() && rest.is_empty());
+
+        let (in_the_way, rest) = with_instances(2).stale_units(&with_instances(3));
+        assert!(in_the_way.is_empty());
+        assert_eq!(names(&rest), vec!["blue-app@9002"]);
+    }
+
+    #[test]
+    fn each_service_carries_on_past_failures() {
+        let services = with_instances(3).instances();
+        let mut tried = vec![];
+        let failures = each_service(&services, |service| {
+            tried.push(service.port);
+            match service.port {
+                9001 => bail!("port in use"),
+                _ => Ok(()),
+            }
+        });
+
+        assert_eq!(tried, vec![9000, 9001, 9002]);
+        assert_eq!(failures, vec!["blue-app@9001: port in use"]);
+        assert!(failed(failures).is_err());
+    }
+
+    #[test]
+    fn binaries_run_from_the_working_dir_when_theyre_part_of_the_release() {
+        assert_eq!(bin_path("/srv/app", "/srv/app/target/app", "/srv/blue-app", "blue-app"), "/srv/blue-app/target/app");
+        assert_eq!(bin_path("/srv/app", "/opt/app/app", "/srv/blue-app", "blue-app"), format!("{}/blue-app", settings().bin_dir()));
+    }
+
+    #[test]
+    fn only_commands_running_the_legacy_binary_are_rewritten() {
+        let bin = "/srv/blue-app/app";
+        assert_eq!(unlegacy_command("/usr/local/bin/blue-app --port 9000", "blue-app", bin).as_deref(), Some("/srv/blue-app/app --port 9000"));
+        assert_eq!(unlegacy_command("blue-app", "blue-app", bin).as_deref(), Some(bin));
+        assert_eq!(unlegacy_command("blue-app-migrate", "blue-app", bin), None);
+        assert_eq!(unlegacy_command("/usr/local/bin/other", "blue-app", bin), None);
+        assert_eq!(unlegacy_command("curl localhost:9000", "blue-app", bin), None);
+    }
+
+    #[test]
+    fn loading_points_legacy_commands_at_the_new_binary() {
+        let path = std::env::temp_dir().join(format!("dorc-legacy-bins-{}.toml", std::process::id()));
+        std::fs::write(&path, r#"
+            app_name = "app"
+            release_dir = "/srv/app"
+            release_bin = "/srv/app/app"
+            active = "blue"
+
+            [[slots]]
+            qualified_name = "blue-app"
+            working_dir = "/srv/blue-app"
+            port = 9000
+            on_start = "/usr/local/bin/blue-app --port 9000"
+            on_reload = ["blue-app reload", "echo reloaded"]
+            on_stop = ["killall blue-app"]
+
+            [[slots]]
+            qualified_name = "green-app"
+            working_dir = "/srv/green-app"
+            port = 9001
+            on_start = "/srv/green-app/app --port 9001"
+        "#).unwrap();
+        let app = App::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let app = app.unwrap();
+
+        let blue = app.slot("blue").unwrap();
+        assert_eq!(blue.on_start, "/srv/blue-app/app --port 9000");
+        assert_eq!(blue.on_reload, Some(vec!["/srv/blue-app/app reload".to_string(), "echo reloaded".to_string()]));
+        assert_eq!(blue.on_stop, None);
+        assert_eq!(app.slot("green").unwrap().on_start, "/srv/green-app/app --port 9001");
+        assert_eq!(app.legacy_bins, vec!["blue-app"]);
+    }
 }
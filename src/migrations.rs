@@ -0,0 +1,102 @@
+use anyhow::*;
+use log::*;
+use toml::Value;
+use toml::value::Table;
+
+/// The version of app config this dorc writes, bumped by every change older configs need upgrading for.
+pub(crate) const CURRENT_VERSION: u32 = 1;
+
+// MIGRATIONS[n] upgrades a version n config to version n + 1
+const MIGRATIONS: &[fn(&mut Table) -> Result<()>] = &[
+    slots_from_active_and_inactive,
+];
+
+/// Version 0 had exactly two services, `active_service` and `inactive_service`.
+fn slots_from_active_and_inactive(app: &mut Table) -> Result<()> {
+    let mut slots = match app.remove("slots") {
+        Some(Value::Array(slots)) => slots,
+        Some(_) => bail!("`slots` should be a list of tables"),
+        None => vec![],
+    };
+
+    if let Some(active) = app.remove("active_service") {
+        let color = active.get("qualified_name")
+            .and_then(Value::as_str)
+            .and_then(|name| name.split('-').next())
+            .ok_or_else(|| anyhow!("`active_service.qualified_name` is missing"))?;
+        app.insert("active".to_string(), Value::String(color.to_string()));
+        slots.insert(0, active);
+    }
+    if let Some(inactive) = app.remove("inactive_service") {
+        slots.push(inactive);
+    }
+
+    app.insert("slots".to_string(), Value::Array(slots));
+    Ok(())
+}
+
+/// Brings a parsed app config up to CURRENT_VERSION, returning the version it was written as.
+pub(crate) fn migrate(app: &mut Value) -> Result<u32> {
+    let table = app.as_table_mut().ok_or_else(|| anyhow!("expected a table of settings"))?;
+    let version = match table.get("version") {
+        None => 0, // written before configs were versioned
+        Some(Value::Integer(version)) if *version >= 0 => *version as u32,
+        Some(_) => bail!("`version` should be a whole number"),
+    };
+
+    if version > CURRENT_VERSION {
+        bail!("written by a newer dorc (config version {}), this one understands up to version {}", version, CURRENT_VERSION);
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(table).map_err(|e| anyhow!("could not upgrade from config version {}: {}", from, e))?;
+        debug!("upgraded config from version {} to {}", from, from + 1);
+    }
+
+    table.insert("version".to_string(), Value::Integer(CURRENT_VERSION as i64));
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Value {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn turns_two_services_into_slots() {
+        let mut app = parse(r#"
+            app_name = "app"
+            [active_service]
+            qualified_name = "green-app"
+            [inactive_service]
+            qualified_name = "blue-app"
+        "#);
+
+        assert_eq!(migrate(&mut app).unwrap(), 0);
+        assert_eq!(app["version"].as_integer(), Some(CURRENT_VERSION as i64));
+        assert_eq!(app["active"].as_str(), Some("green"));
+        let names: Vec<&str> = app["slots"].as_array().unwrap().iter()
+            .map(|slot| slot["qualified_name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["green-app", "blue-app"]);
+        assert!(app.get("active_service").is_none() && app.get("inactive_service").is_none());
+    }
+
+    #[test]
+    fn leaves_current_configs_alone() {
+        let toml = format!("version = {}\nactive = \"blue\"\nslots = []\n", CURRENT_VERSION);
+        let mut app = parse(&toml);
+        assert_eq!(migrate(&mut app).unwrap(), CURRENT_VERSION);
+        assert_eq!(app, parse(&toml));
+    }
+
+    #[test]
+    fn rejects_newer_and_broken_configs() {
+        assert!(migrate(&mut parse(&format!("version = {}", CURRENT_VERSION + 1))).is_err());
+        assert!(migrate(&mut parse("version = -1")).is_err());
+        assert!(migrate(&mut parse("[active_service]\nport = 1")).is_err());
+    }
+}
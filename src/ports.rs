@@ -0,0 +1,146 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::Path;
+
+use anyhow::*;
+use toml::Value;
+
+use crate::migrations;
+use crate::settings::settings;
+
+/// Picks free ports from `port_range` in dorc.toml.
+pub(crate) struct PortAllocator {
+    taken: BTreeSet<u16>,
+}
+
+impl PortAllocator {
+    /// Avoids every port the other apps' configs use. `app_name`'s own config is left out,
+    /// since it's the one ports are being picked for.
+    pub(crate) fn new(app_name: Option<&str>) -> PortAllocator {
+        let mut taken = BTreeSet::new();
+        let own = app_name.map(|name| settings().app_path(name));
+
+        for path in settings().app_paths().unwrap_or_default() {
+            if Some(&path) != own.as_ref() {
+                // read raw, so apps that don't load still count
+                taken.extend(ports_in(&path));
+            }
+        }
+
+        PortAllocator { taken }
+    }
+
+    /// Marks ports that were picked some other way, so they aren't handed out again.
+    pub(crate) fn reserve(&mut self, port: u16, count: u16) {
+        self.taken.extend((0..count.max(1)).map(|i| port.saturating_add(i)));
+    }
+
+    /// The first of `count` consecutive free ports, reserving them.
+    pub(crate) fn allocate(&mut self, count: u16) -> Result<u16> {
+        let port = self.peek(count)?;
+        self.reserve(port, count);
+        Ok(port)
+    }
+
+    /// What `allocate` would pick, without reserving it, e.g. to suggest.
+    pub(crate) fn peek(&self, count: u16) -> Result<u16> {
+        let [first, last] = settings().port_range;
+        let count = count.max(1);
+
+        let mut port = first;
+        while let Some(end) = port.checked_add(count - 1).filter(|end| *end <= last) {
+            match (port..=end).find(|p| self.taken.contains(p) || !is_free(*p)) {
+                // nothing up to a taken port can start a long enough run
+                Some(taken) => port = match taken.checked_add(1) {
+                    Some(next) => next,
+                    None => break,
+                },
+                None => return Ok(port),
+            }
+        }
+
+        bail!("no {} free consecutive ports left in port_range {}-{}", count, first, last)
+    }
+}
+
+/// Whether nothing on this host is listening on `port`.
+fn is_free(port: u16) -> bool {
+    TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+}
+
+/// The listen port and every service port in an app's config.
+fn ports_in(path: &Path) -> Vec<u16> {
+    let mut app: Value = match std::fs::read_to_string(path).ok().and_then(|toml| toml::from_str(&toml).ok()) {
+        Some(app) => app,
+        None => return vec![],
+    };
+    if migrations::migrate(&mut app).is_err() {
+        return vec![];
+    }
+
+    let port = |value: Option<&Value>| value.and_then(Value::as_integer).and_then(|p| u16::try_from(p).ok()).filter(|p| *p != 0);
+    let mut ports: Vec<u16> = port(app.get("listen_port")).into_iter().collect();
+
+    for slot in app.get("slots").and_then(Value::as_array).into_iter().flatten() {
+        if let Some(first) = port(slot.get("port")) {
+            let instances = slot.get("instances").and_then(Value::as_integer).and_then(|n| u16::try_from(n).ok()).unwrap_or(1).max(1);
+            ports.extend((0..instances).map(|i| first.saturating_add(i)));
+        }
+    }
+    ports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocator() -> PortAllocator {
+        PortAllocator { taken: BTreeSet::new() }
+    }
+
+    #[test]
+    fn peeking_reserves_nothing() {
+        let mut ports = allocator();
+        let suggested = ports.peek(1).unwrap();
+        assert_eq!(ports.peek(1).unwrap(), suggested);
+
+        // the user picked another port, so the suggestion is still up for grabs
+        ports.reserve(suggested + 100, 1);
+        assert_eq!(ports.allocate(1).unwrap(), suggested);
+        assert_ne!(ports.peek(1).unwrap(), suggested);
+    }
+
+    #[test]
+    fn runs_skip_taken_and_listening_ports() {
+        let mut ports = allocator();
+        let first = ports.peek(1).unwrap();
+        ports.reserve(first + 1, 1);
+        let start = ports.allocate(2).unwrap();
+        assert!(start > first + 1);
+
+        let _listening = TcpListener::bind((Ipv4Addr::UNSPECIFIED, ports.peek(1).unwrap())).unwrap();
+        let next = ports.peek(1).unwrap();
+        assert!(TcpListener::bind((Ipv4Addr::UNSPECIFIED, next)).is_ok());
+    }
+
+    #[test]
+    fn reads_ports_from_configs() {
+        let path = std::env::temp_dir().join(format!("dorc-ports-{}.toml", std::process::id()));
+        std::fs::write(&path, r#"
+            version = 1
+            listen_port = 9100
+            [[slots]]
+            port = 9121
+            instances = 3
+            [[slots]]
+            port = 70000
+            [[slots]]
+            port = 0
+        "#).unwrap();
+
+        let ports = ports_in(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(ports, vec![9100, 9121, 9122, 9123]);
+    }
+}
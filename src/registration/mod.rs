@@ -1,72 +1,272 @@
-use log::{info, error};
+use log::{info, error, warn};
 
-use dialoguer::console::style;
-use dialoguer::Input;
+use dialoguer::console::{style, Color};
+use dialoguer::{Confirm, Input, Select, Validator};
 use dialoguer::theme::ColorfulTheme;
 
 use crate::App;
-use crate::registration::types::Service;
-use crate::registration::validators::{AddressValidator, AppNameValidator, FileValidator, LocationValidator};
-use crate::daemon::FIFO;
+use crate::registration::types::{ServiceOverrides, ServiceTemplate, SystemdConfig};
+use crate::registration::validators::{AddressValidator, AppNameValidator, DurationValidator, FileValidator, LocationValidator, NumberValidator, TemplateValidator};
+use crate::daemon::balance::Strategy;
+use crate::ports::PortAllocator;
+use crate::settings::settings;
+use crate::supervisor::SupervisorKind;
+use crate::supervisor::systemd::Systemd1;
 use std::process::Command;
+use std::time::Duration;
 
 pub(crate) mod types;
 pub mod validators;
 
+// slot names can't contain `-`, it separates them from the app name
+pub(crate) const SLOTS: &[(&str, Color)] = &[
+    ("blue", Color::Blue),
+    ("green", Color::Green),
+    ("red", Color::Red),
+    ("yellow", Color::Yellow),
+    ("magenta", Color::Magenta),
+    ("cyan", Color::Cyan),
+];
+
+/// The color a slot's name is printed in.
+pub(crate) fn slot_color(name: &str) -> Color {
+    SLOTS.iter().find(|(slot, _)| *slot == name).map(|(_, color)| *color).unwrap_or(Color::White)
+}
+
+
+impl ServiceTemplate {
+    pub(crate) fn from_stdin() -> Self {
+        println!(
+            "{} {}, {}, {}, {} and {} are filled in for each color.",
+            style("Placeholders:").bold(),
+            style("{app}").cyan(),
+            style("{color}").cyan(),
+            style("{port}").cyan(),
+            style("{working_dir}").cyan(),
+            style("{bin}").cyan()
+        );
 
-impl Service {
-    pub(crate) fn from_stdin(qualified_name: String) -> Self {
         // TODO: for any inputs using directories impl tab-completion.
         let working_dir = Input::with_theme(&ColorfulTheme::default())
             .with_prompt("Working dir")
-            .default(format!("/etc/dorc/service-data/{}", qualified_name))
+            .default(format!("{}/{{color}}-{{app}}", settings().service_data_dir))
             .show_default(true)
-            .validate_with(LocationValidator)
+            .validate_with(|s: &String| {
+                TemplateValidator.validate(s)?;
+                LocationValidator.validate(s)
+            })
             .interact_text()
             .unwrap();
 
-        // TODO: print helper text here
-        let port_str: String = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Service address")
-            .validate_with(AddressValidator)
+        let instances: u16 = Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt("Instances per slot (each gets its own port)")
+            .default("1".to_string())
+            .validate_with(|s: &String| match s.parse::<u16>() {
+                Ok(n) if n >= 1 => Ok(()),
+                _ => Err("Must be at least 1."),
+            })
             .interact_text()
+            .unwrap()
+            .parse()
             .unwrap();
 
-        let port = port_str.parse().unwrap();
+        let on_start = template_input("Start command", Some("{bin} -p {port}")).unwrap();
+        // left empty, the service's main process is sent SIGTERM
+        let on_stop = template_input("Stop command", None);
+        let on_reload = template_input("Reload command", None);
 
-        let on_start = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Start command")
-            .default(format!("{} -p {}", qualified_name, port))
-            .show_default(true)
-            .interact_text()
-            .unwrap();
+        Self { working_dir, instances, on_start, on_reload, on_stop }
+    }
+}
 
-        let on_stop: String = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Stop command")
-            .default(format!("killall {}", qualified_name))
-            .show_default(true)
+impl ServiceOverrides {
+    pub(crate) fn from_stdin() -> Self {
+        println!("Leave any of these empty to use the template.");
+
+        let working_dir = Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt("Working dir")
+            .allow_empty(true)
+            .validate_with(|s: &String| {
+                if s.is_empty() {
+                    return Ok(());
+                }
+                TemplateValidator.validate(s)?;
+                LocationValidator.validate(s)
+            })
             .interact_text()
             .unwrap();
 
-        let on_reload: String = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Reload command")
+        Self {
+            working_dir: optional(working_dir),
+            on_start: template_input("Start command", None),
+            on_stop: template_input("Stop command", None),
+            on_reload: template_input("Reload command", None),
+        }
+    }
+}
+
+fn template_input(prompt: &str, default: Option<&str>) -> Option<String> {
+    let theme = ColorfulTheme::default();
+    let mut input = Input::with_theme(&theme);
+    input.with_prompt(prompt).validate_with(TemplateValidator);
+    match default {
+        Some(default) => input.default(default.to_string()).show_default(true),
+        None => input.allow_empty(true),
+    };
+    optional(input.interact_text().unwrap())
+}
+
+/// Offers `count` free ports from `port_range`, and reserves whatever's picked.
+fn port_from_stdin(prompt: &str, ports: &mut PortAllocator, count: u16) -> u16 {
+    let theme = ColorfulTheme::default();
+    let mut input = Input::<String>::with_theme(&theme);
+    input.with_prompt(prompt).validate_with(move |s: &String| -> Result<(), String> {
+        AddressValidator.validate(s)?;
+        match s.parse::<u16>().ok().and_then(|port| port.checked_add(count.max(1) - 1)) {
+            Some(_) => Ok(()),
+            None => Err(format!("{} instances starting at {} don't fit below port 65536.", count, s)),
+        }
+    });
+    // only the port picked is reserved, which may not be the one suggested
+    match ports.peek(count) {
+        Ok(free) => input.default(free.to_string()).show_default(true),
+        Err(e) => {
+            warn!("could not pick a free port | {}", e);
+            &mut input
+        }
+    };
+
+    let port: u16 = input.interact_text().unwrap().parse().unwrap();
+    ports.reserve(port, count);
+    port
+}
+
+/// `dorc-dwbrite-com` for `dwbrite.com`, since dots aren't safe in user names
+fn service_user_name(app_name: &str) -> String {
+    let name = format!("dorc-{}", app_name.to_lowercase().replace('.', "-"));
+    name.chars().take(32).collect()
+}
+
+fn create_system_user(name: &str) -> anyhow::Result<()> {
+    let exists = Command::new("id").args(["-u", name]).output()?.status.success();
+    if exists {
+        return Ok(());
+    }
+
+    let output = Command::new("useradd")
+        .args(["--system", "--no-create-home", "--shell", "/usr/sbin/nologin", name])
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(())
+}
+
+/// Installs and enables the app's socket unit. systemd refuses to start a socket while its service is running,
+/// and stopping dorc would drop every app's connections, so with the daemon up the socket waits for the next boot
+/// and the daemon binds the port itself until then.
+fn install_socket_unit(app: &App, daemon_active: bool) -> anyhow::Result<()> {
+    std::fs::create_dir_all(&settings().unit_dir)?;
+    std::fs::write(
+        format!("{}/{}", settings().unit_dir, app.socket_unit_name()),
+        app.to_systemd_socket(),
+    )?;
+
+    let systemd = Systemd1::connect()?;
+    systemd.daemon_reload()?;
+    systemd.enable(&app.socket_unit_name())?;
+
+    if daemon_active {
+        println!(
+            "{} takes over port {} from the next boot. Until then the daemon holds it, and `dorc upgrade` keeps it open.",
+            app.socket_unit_name(),
+            app.listen_port
+        );
+        println!(
+            "To move it to the socket now, which briefly drops every app's connections: systemctl stop dorc && systemctl start {} dorc",
+            app.socket_unit_name()
+        );
+    } else {
+        // the daemon picks it up when it starts
+        systemd.start(&app.socket_unit_name())?;
+    }
+
+    Ok(())
+}
+
+fn optional(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn optional_input(prompt: &str) -> Option<String> {
+    optional(Input::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .allow_empty(true)
+        .interact_text()
+        .unwrap())
+}
+
+fn optional_duration(prompt: &str) -> Option<Duration> {
+    let value: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .allow_empty(true)
+        .validate_with(DurationValidator)
+        .interact_text()
+        .unwrap();
+
+    optional(value).map(|d| humantime::parse_duration(&d).unwrap())
+}
+
+impl SystemdConfig {
+    pub(crate) fn from_stdin() -> Self {
+        println!("Leave any of these empty to use systemd's default.");
+
+        let user = optional_input("User");
+        let group = optional_input("Group");
+        let restart = optional_input("Restart (e.g. on-failure, always)");
+        let restart_sec = optional_duration("RestartSec");
+
+        let limit_nofile: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("LimitNOFILE")
             .allow_empty(true)
+            .validate_with(NumberValidator)
             .interact_text()
             .unwrap();
+        let limit_nofile = optional(limit_nofile).map(|n| n.parse().unwrap());
+
+        let memory_max = optional_input("MemoryMax (e.g. 512M)");
+        let cpu_quota = optional_input("CPUQuota (e.g. 50%)");
+        let timeout_stop_sec = optional_duration("TimeoutStopSec");
+
+        let mut extra = vec![];
+        while let Some(directive) = optional_input("Extra [Service] directive (Key=Value, empty to finish)") {
+            extra.push(directive);
+        }
 
         Self {
-            qualified_name,
-            working_dir,
-            port,
-            on_start,
-            on_reload: Some(vec![on_reload]),
-            on_stop: Some(vec![on_stop]),
+            user,
+            group,
+            restart,
+            restart_sec,
+            limit_nofile,
+            memory_max,
+            cpu_quota,
+            timeout_stop_sec,
+            sandbox: false,
+            extra,
         }
     }
 }
 
 pub fn register() {
-    sudo::escalate_if_needed().expect("Higher privilege required to write service files.");
+    if settings().needs_root() {
+        sudo::escalate_if_needed().expect("Higher privilege required to write service files.");
+    }
 
     let app_name: String = Input::with_theme(&ColorfulTheme::default())
         .with_prompt("App name")
@@ -76,7 +276,7 @@ pub fn register() {
 
     let release_dir: String = Input::with_theme(&ColorfulTheme::default())
         .with_prompt("Release location")
-        .default(format!("/var/tmp/{}", app_name))
+        .default(settings().under_root(&format!("/var/tmp/{}", app_name)))
         .show_default(true)
         .validate_with(LocationValidator)
         .interact_text()
@@ -88,86 +288,209 @@ pub fn register() {
         .interact_text()
         .unwrap();
 
-    let listen_port: u16 = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Listen port")
-        .validate_with(AddressValidator)
-        .interact_text()
-        .unwrap()
-        .parse()
+    let mut ports = PortAllocator::new(Some(&app_name));
+    let listen_port = port_from_stdin("Listen port", &mut ports, 1);
+
+    // under a root, only the built-in supervisor sees what dorc writes
+    let use_systemd = settings().systemd_sees_units();
+    if !use_systemd {
+        println!("Services under {} are run by dorc's built-in supervisor, systemd wouldn't see them.", settings().root.as_deref().unwrap_or_default());
+    }
+
+    let socket_activated = use_systemd && Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Let systemd hold the listen port? (connections survive daemon restarts)")
+        .default(false)
+        .interact()
         .unwrap();
 
+    let supervisor = if !use_systemd {
+        SupervisorKind::Builtin
+    } else {
+        match Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Run services with")
+            .items(&["systemd", "dorc's built-in supervisor (no systemd needed)"])
+            .default(0)
+            .interact()
+            .unwrap()
+        {
+            0 => SupervisorKind::Systemd,
+            _ => SupervisorKind::Builtin,
+        }
+    };
+
     println!();
     println!(
         "This tool is for {}/{} deployments.",
         style("blue").blue(),
         style("green").green()
     );
+    println!("A third slot keeps the last two releases warm, for instant rollbacks.");
+
+    let slot_count: usize = Input::<String>::with_theme(&ColorfulTheme::default())
+        .with_prompt("Slots")
+        .default("2".to_string())
+        .validate_with(|s: &String| match s.parse::<usize>() {
+            Ok(n) if (2..=SLOTS.len()).contains(&n) => Ok(()),
+            _ => Err(format!("Pick between 2 and {} slots.", SLOTS.len())),
+        })
+        .interact_text()
+        .unwrap()
+        .parse()
+        .unwrap();
+
     println!(
         "Let's configure {}'s sub-services.",
         style(&app_name).yellow().bold()
     );
 
-    let blue_service_name = format!("blue-{}", app_name);
-    println!(
-        "{}",
-        style(format!("\nConfiguring '{}'", blue_service_name))
-            .blue()
-            .bold()
-    );
-    let blue_service = Service::from_stdin(blue_service_name.clone());
+    let template = ServiceTemplate::from_stdin();
 
-    let green_service_name = format!("green-{}", app_name);
-    println!(
-        "{}",
-        style(format!("\nConfiguring '{}'", green_service_name))
-            .green()
-            .bold()
-    );
-    let green_service = Service::from_stdin(green_service_name.clone());
+    let balance = if template.instances > 1 {
+        match Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Spread connections across instances by")
+            .items(&["round-robin", "least connections"])
+            .default(0)
+            .interact()
+            .unwrap()
+        {
+            0 => Strategy::RoundRobin,
+            _ => Strategy::LeastConnections,
+        }
+    } else {
+        Strategy::default()
+    };
+    let port_prompt = if template.instances > 1 {
+        format!("Service address (ports {{port}} to {{port}} + {})", template.instances - 1)
+    } else {
+        "Service address".to_string()
+    };
 
-    let app = App {
+    let mut slots = vec![];
+    for (color, fg) in &SLOTS[..slot_count] {
+        let name = format!("{}-{}", color, app_name);
+        println!("{}", style(format!("\nConfiguring '{}'", name)).fg(*fg).bold());
+
+        let port = port_from_stdin(&port_prompt, &mut ports, template.instances);
+        let overrides = if Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Override the template for {}?", color))
+            .default(false)
+            .interact()
+            .unwrap()
+        {
+            ServiceOverrides::from_stdin()
+        } else {
+            ServiceOverrides::default()
+        };
+
+        slots.push(template.expand(&app_name, color, port, &overrides, (&release_dir, &release_bin)));
+    }
+
+    let configure_systemd = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Configure the user, restart policy, or limits of every slot's service?")
+        .default(false)
+        .interact()
+        .unwrap();
+
+    let mut systemd = if configure_systemd {
+        SystemdConfig::from_stdin()
+    } else {
+        SystemdConfig::default()
+    };
+
+    let user = service_user_name(&app_name);
+    // creating users takes root, and services in user mode run as whoever runs dorc anyway
+    let sandbox = !settings().user_mode && Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("Run every slot's service as a dedicated, sandboxed '{}' user?", user))
+        .default(true)
+        .interact()
+        .unwrap();
+
+    if sandbox {
+        match create_system_user(&user) {
+            Ok(_) => {
+                systemd.user = Some(user.clone());
+                systemd.group = Some(user);
+                systemd.sandbox = true;
+            }
+            Err(e) => error!("failed to create user {}, services will run as root | {}", user, e),
+        }
+    }
+
+    let mut app = App {
+        version: crate::migrations::CURRENT_VERSION,
         app_name,
         release_dir,
         release_bin,
         listen_port,
-        active_service: green_service,
-        inactive_service: blue_service,
+        listen_address: None,
+        socket_activated,
+        supervisor,
+        grace_period: None,
+        // green, which was active when every app had just blue and green
+        active: SLOTS[1].0.to_string(),
+        balance,
+        health: Default::default(),
+        slots,
+        systemd,
+        access_log: None,
+        access: None,
+        mirror: None,
+        hooks: Default::default(),
+        sync: Default::default(),
+        environment_files: vec![],
+        environment: Default::default(),
+        legacy_bins: vec![],
+        upgraded_from: None,
     };
 
-    app.save();
-
     // move release files to relevant subservice locations
-    for service in &[&app.inactive_service, &app.active_service]  {
-        match app.migrate_service(&service) {
+    for slot in 0..app.slots.len() {
+        app.slots[slot].release_id = Some(crate::new_release_id());
+        let service = &app.slots[slot];
+        match app.migrate_service(service) {
             Ok(_) => {
                 info!("successfully migrated files from {} to {} for {}", app.release_dir, service.working_dir, service.qualified_name);
             }
             Err(e) => {
                 error!("failed to migrate files for {} | {}", service.qualified_name, e);
+                app.slots[slot].release_id = None;
             }
         }
     }
+    app.save();
 
     // automatically load the service if the dorc daemon is running
-    let output = std::process::Command::new("systemctl")
-        .args(&["is-active", "dorc"])
-        .output()
-        .unwrap();
+    let daemon_active = if use_systemd {
+        match Systemd1::connect().and_then(|systemd| systemd.active_state("dorc")) {
+            Ok(state) => state == "active",
+            Err(e) => {
+                error!("could not check whether the dorc daemon is running | {}", e);
+                false
+            }
+        }
+    } else {
+        println!("If the daemon is running, `dorc load {}` starts the app.", app.app_name);
+        false
+    };
 
-    if output.stdout.eq("active".as_bytes()) {
+    if app.socket_activated {
+        if let Err(e) = install_socket_unit(&app, daemon_active) {
+            error!("failed to install {} | {}", app.socket_unit_name(), e);
+        }
+    }
+    if daemon_active {
         println!("Attempting to load app in daemon...");
         println!("If this command hangs, make sure the daemon is running successfully.");
-        Command::new("sh")
-            .arg("-c")
-            .arg(format!("echo 'load {}' > {}", app.app_name, FIFO))
-            .output()
-            .expect("failed to execute process");
+        if let Err(e) = crate::daemon::admin::send(&format!("load {}", app.app_name)) {
+            error!("failed to load {} in the daemon | {}", app.app_name, e);
+        }
     }
 
 
     println!(
-        "\nDone! {} has been registered with two services.",
-        style(&app.app_name).yellow().bold()
+        "\nDone! {} has been registered with {} services.",
+        style(&app.app_name).yellow().bold(),
+        app.slots.len()
     );
 
     println!("Thanks for using dorc!~");
@@ -1,20 +1,240 @@
 use core::option::Option::{None, Some};
 use core::option::Option;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use systemd_unit::EnvVar;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::App;
+use crate::secrets::secrets_path;
+use crate::settings::settings;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Service {
     pub(crate) qualified_name: String,
     pub(crate) working_dir: String, // defaults to /srv/www/<qualified-service-name>
-    pub(crate) port: u16,
+    #[serde(default)]
+    pub(crate) port: u16, // 0 or left out is picked from `port_range` on load
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub(crate) instances: u16, // each on its own port, counting up from `port`
 
     pub(crate) on_start: String,
     pub(crate) on_reload: Option<Vec<String>>,
     pub(crate) on_stop: Option<Vec<String>>, // defaults to kill <pid>
+
+    pub(crate) release_id: Option<String>, // set each time a release is copied in
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) environment_files: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) environment: BTreeMap<String, String>, // on top of the app's environment
+}
+
+fn one() -> u16 {
+    1
+}
+
+fn is_one(n: &u16) -> bool {
+    *n == 1
+}
+
+/// How both colors' services are run. `{app}`, `{color}`, `{port}`, `{working_dir}` and `{bin}`
+/// are filled in for each color when it's expanded into a `Service`.
+#[derive(Debug, Clone)]
+pub struct ServiceTemplate {
+    pub(crate) working_dir: String,
+    pub(crate) instances: u16,
+    pub(crate) on_start: String,
+    pub(crate) on_reload: Option<String>,
+    pub(crate) on_stop: Option<String>,
+}
+
+/// Whatever one color does differently from the template.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceOverrides {
+    pub(crate) working_dir: Option<String>,
+    pub(crate) on_start: Option<String>,
+    pub(crate) on_reload: Option<String>,
+    pub(crate) on_stop: Option<String>,
+}
+
+pub(crate) const TEMPLATE_PLACEHOLDERS: &[&str] = &["app", "color", "port", "working_dir", "bin"];
+
+fn fill(template: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter().fold(template.to_string(), |filled, (name, value)| {
+        filled.replace(&format!("{{{}}}", name), value)
+    })
+}
+
+impl ServiceTemplate {
+    /// `release` is the app's release dir and binary, which `{bin}` is worked out from.
+    pub(crate) fn expand(&self, app_name: &str, color: &str, port: u16, overrides: &ServiceOverrides, release: (&str, &str)) -> Service {
+        let port_str = port.to_string();
+        let qualified_name = format!("{}-{}", color, app_name);
+        let mut vars = vec![("app", app_name), ("color", color), ("port", port_str.as_str())];
+
+        let working_dir = fill(overrides.working_dir.as_ref().unwrap_or(&self.working_dir), &vars);
+        let bin = crate::bin_path(release.0, release.1, &working_dir, &qualified_name);
+        // commands get each instance's own port, see `Service::instances`
+        vars.retain(|(name, _)| *name != "port");
+        vars.push(("working_dir", &working_dir));
+        vars.push(("bin", &bin));
+
+        let on_start = overrides.on_start.as_ref().unwrap_or(&self.on_start);
+        let on_reload = overrides.on_reload.as_ref().or(self.on_reload.as_ref());
+        let on_stop = overrides.on_stop.as_ref().or(self.on_stop.as_ref());
+
+        Service {
+            qualified_name,
+            working_dir: working_dir.clone(),
+            port,
+            instances: self.instances,
+            on_start: fill(on_start, &vars),
+            on_reload: on_reload.map(|c| vec![fill(c, &vars)]),
+            on_stop: on_stop.map(|c| vec![fill(c, &vars)]),
+            release_id: None, // stamped once a release has been copied in
+            environment_files: vec![],
+            environment: Default::default(),
+        }
+    }
+}
+
+/// Extra settings for the generated systemd units, shared by both services.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemdConfig {
+    pub(crate) user: Option<String>,
+    pub(crate) group: Option<String>,
+    pub(crate) restart: Option<String>, // e.g. on-failure, always
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub(crate) restart_sec: Option<Duration>,
+    pub(crate) limit_nofile: Option<u64>,
+    pub(crate) memory_max: Option<String>, // e.g. 512M
+    pub(crate) cpu_quota: Option<String>, // e.g. 50%
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub(crate) timeout_stop_sec: Option<Duration>,
+    #[serde(default)]
+    pub(crate) sandbox: bool, // read-only system, private /tmp, only the working dir is writable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) extra: Vec<String>, // raw `Key=Value` lines for the [Service] section
+}
+
+/// Hardening directives every sandboxed unit gets, and `dorc doctor` looks for.
+pub(crate) const SANDBOX_DIRECTIVES: &[&str] = &[
+    "ProtectSystem=strict",
+    "NoNewPrivileges=yes",
+    "PrivateTmp=yes",
+];
+
+impl SystemdConfig {
+    pub(crate) fn restart_sec(&self) -> Option<Duration> {
+        self.restart_sec.or(settings().defaults.restart_sec)
+    }
+
+    pub(crate) fn timeout_stop_sec(&self) -> Option<Duration> {
+        self.timeout_stop_sec.or(settings().defaults.timeout_stop_sec)
+    }
+
+    /// [Service] directives that systemd_unit can't print for us.
+    fn directives(&self, working_dir: &str) -> Vec<String> {
+        let mut directives = vec![];
+
+        if let Some(v) = &self.restart {
+            directives.push(format!("Restart={}", v));
+        }
+        if let Some(v) = &self.restart_sec() {
+            directives.push(format!("RestartSec={}", humantime::format_duration(*v)));
+        }
+        if let Some(v) = &self.limit_nofile {
+            directives.push(format!("LimitNOFILE={}", v));
+        }
+        if let Some(v) = &self.memory_max {
+            directives.push(format!("MemoryMax={}", v));
+        }
+        if let Some(v) = &self.cpu_quota {
+            directives.push(format!("CPUQuota={}", v));
+        }
+        if let Some(v) = &self.timeout_stop_sec() {
+            directives.push(format!("TimeoutStopSec={}", humantime::format_duration(*v)));
+        }
+        if self.sandbox {
+            directives.extend(SANDBOX_DIRECTIVES.iter().map(|d| d.to_string()));
+            directives.push(format!("ReadWritePaths={}", working_dir));
+        }
+
+        directives.extend(self.extra.iter().cloned());
+        directives
+    }
 }
 
 impl Service {
-    pub fn to_systemd_service(&self) -> systemd_unit::Service {
+    /// `blue` for `blue-dwbrite.com`
+    pub fn color(&self) -> &str {
+        self.qualified_name.split('-').next().unwrap_or(&self.qualified_name)
+    }
+
+    /// `blue-dwbrite.com` for `blue-dwbrite.com@41236`
+    pub(crate) fn slot_name(&self) -> &str {
+        self.qualified_name.split('@').next().unwrap_or(&self.qualified_name)
+    }
+
+    /// The last port this slot's instances use, if they fit below 65536.
+    pub(crate) fn last_port(&self) -> Option<u16> {
+        self.port.checked_add(self.instances.max(1) - 1)
+    }
+
+    /// One `Service` per instance, with `{port}` in its commands filled in.
+    /// A single instance keeps the slot's name, more are named like `blue-dwbrite.com@41236`.
+    pub(crate) fn instances(&self) -> Vec<Service> {
+        // `App::load` rejects slots whose ports run out, so none are left off here
+        (0..self.instances.max(1)).map_while(|i| self.port.checked_add(i)).map(|port| {
+            let fill_port = |command: &String| command.replace("{port}", &port.to_string());
+            let qualified_name = if self.instances > 1 {
+                format!("{}@{}", self.slot_name(), port)
+            } else {
+                self.qualified_name.clone()
+            };
+
+            Service {
+                qualified_name,
+                port,
+                instances: 1,
+                on_start: fill_port(&self.on_start),
+                on_reload: self.on_reload.as_ref().map(|c| c.iter().map(fill_port).collect()),
+                on_stop: self.on_stop.as_ref().map(|c| c.iter().map(fill_port).collect()),
+                ..self.clone()
+            }
+        }).collect()
+    }
+
+    pub(crate) fn unit_path(&self) -> String {
+        format!("{}/{}.service", settings().unit_dir, self.qualified_name)
+    }
+
+    /// The app's environment, then this service's, then what dorc tells every service about itself.
+    pub(crate) fn environment(&self, app: &App) -> Vec<(String, String)> {
+        let mut env: BTreeMap<String, String> = app.environment.clone();
+        env.extend(self.environment.clone());
+
+        env.insert("DORC_APP".to_string(), app.app_name.clone());
+        env.insert("DORC_COLOR".to_string(), self.color().to_string());
+        env.insert("DORC_PORT".to_string(), self.port.to_string());
+        if let Some(release_id) = &self.release_id {
+            env.insert("DORC_RELEASE_ID".to_string(), release_id.clone());
+        }
+
+        env.into_iter().collect()
+    }
+
+    /// Env files in the order systemd reads them, ending with the app's and this service's secrets.
+    pub(crate) fn environment_files(&self, app: &App) -> Vec<String> {
+        let mut files: Vec<String> = app.environment_files.iter().chain(&self.environment_files).cloned().collect();
+        // `-` lets the service start when there are no secrets yet
+        files.push(format!("-{}", secrets_path(&app.app_name, None)));
+        files.push(format!("-{}", secrets_path(&app.app_name, Some(self.color()))));
+        files
+    }
+
+    pub fn to_systemd_service(&self, app: &App) -> systemd_unit::Service {
+        let config = &app.systemd;
         systemd_unit::Service {
             unit: systemd_unit::Unit {
                 name: self.qualified_name.clone(),
@@ -22,11 +242,15 @@ impl Service {
                 ..systemd_unit::Unit::default()
             },
             install: systemd_unit::Install {
-                wanted_by: Some(vec!["multi-user.target".to_string()]), // launch when networks are up
+                // launch when networks are up, or when the user's manager starts
+                wanted_by: Some(vec![if settings().user_mode { "default.target" } else { "multi-user.target" }.to_string()]),
                 ..systemd_unit::Install::default()
             },
             exec: systemd_unit::Exec {
                 working_directory: Some(std::path::PathBuf::from(&self.working_dir)),
+                user: config.user.clone(),
+                group: config.group.clone(),
+                environment: Some(self.environment(app).into_iter().map(|(key, val)| EnvVar { key, val }).collect()),
                 ..systemd_unit::Exec::default()
             },
             exec_start: Some(vec![self.on_start.clone()]),
@@ -35,4 +259,87 @@ impl Service {
             ..systemd_unit::Service::default()
         }
     }
-}
\ No newline at end of file
+
+    /// The full unit file, including directives systemd_unit doesn't support.
+    pub fn to_unit_file(&self, app: &App) -> String {
+        let unit = self.to_systemd_service(app).to_string();
+        let extra: String = self.environment_files(app).iter()
+            .map(|f| format!("EnvironmentFile={}", f))
+            .chain(app.systemd.directives(&self.working_dir))
+            .map(|d| format!("{}\n", d))
+            .collect();
+
+        // the [Service] section ends right before [Install]
+        match unit.find("\n[Install]") {
+            Some(i) => format!("{}{}{}", &unit[..i], extra, &unit[i..]),
+            None => format!("{}{}", unit, extra),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(systemd: &str) -> App {
+        toml::from_str(&format!(r#"
+app_name = "app"
+release_dir = "/srv/app"
+release_bin = "app"
+environment_files = ["/etc/app.env"]
+
+[[slots]]
+qualified_name = "blue-app"
+working_dir = "/srv/blue-app"
+port = 9000
+on_start = "app -p {{port}}"
+
+[systemd]
+{}
+"#, systemd)).unwrap()
+    }
+
+    #[test]
+    fn directives_follow_the_config() {
+        assert!(app("").systemd.directives("/srv/blue-app").is_empty());
+
+        let app = app("restart = \"on-failure\"\nrestart_sec = \"5s\"\nlimit_nofile = 4096\nmemory_max = \"512M\"\ncpu_quota = \"50%\"\ntimeout_stop_sec = \"1m\"\nextra = [\"Nice=5\"]");
+        assert_eq!(app.systemd.directives("/srv/blue-app"), vec![
+            "Restart=on-failure", "RestartSec=5s", "LimitNOFILE=4096", "MemoryMax=512M",
+            "CPUQuota=50%", "TimeoutStopSec=1m", "Nice=5",
+        ]);
+    }
+
+    #[test]
+    fn sandboxing_leaves_only_the_working_dir_writable() {
+        let directives = app("sandbox = true").systemd.directives("/srv/blue-app");
+        assert!(SANDBOX_DIRECTIVES.iter().all(|d| directives.contains(&d.to_string())));
+        assert_eq!(directives.last().map(String::as_str), Some("ReadWritePaths=/srv/blue-app"));
+    }
+
+    #[test]
+    fn unit_files_put_extra_directives_in_the_service_section() {
+        let app = app("user = \"dorc-app\"\nrestart = \"on-failure\"\nextra = [\"Nice=5\"]");
+        let unit = app.slots[0].instances()[0].to_unit_file(&app);
+
+        assert_eq!(unit, "\
+[Unit]
+
+[Service]
+ExecStart=app -p 9000
+WorkingDirectory=/srv/blue-app
+User=dorc-app
+Environment=\"DORC_APP=app\"
+Environment=\"DORC_COLOR=blue\"
+Environment=\"DORC_PORT=9000\"
+EnvironmentFile=/etc/app.env
+EnvironmentFile=-/etc/dorc/secrets/app.env
+EnvironmentFile=-/etc/dorc/secrets/app/blue.env
+Restart=on-failure
+Nice=5
+
+[Install]
+WantedBy=multi-user.target
+");
+    }
+}
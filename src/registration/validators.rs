@@ -1,6 +1,8 @@
 use dialoguer::Validator;
 use std::path::Path;
 
+use crate::registration::types::TEMPLATE_PLACEHOLDERS;
+
 pub struct AppNameValidator;
 impl Validator<String> for AppNameValidator {
     type Err = String;
@@ -66,7 +68,7 @@ impl Validator<String> for AddressValidator {
     type Err = String;
 
     fn validate(&mut self, s: &String) -> Result<(), Self::Err> {
-        if let Ok(_) = s.parse::<u16>() {
+        if s.parse::<u16>().is_ok() {
             Ok(())
         } else {
             Err(String::from(
@@ -75,3 +77,87 @@ impl Validator<String> for AddressValidator {
         }
     }
 }
+
+/// Accepts durations like `5s` or `1m 30s`, or nothing at all.
+pub struct DurationValidator;
+impl Validator<String> for DurationValidator {
+    type Err = String;
+
+    fn validate(&mut self, s: &String) -> Result<(), Self::Err> {
+        if s.is_empty() || humantime::parse_duration(s).is_ok() {
+            Ok(())
+        } else {
+            Err(String::from("Invalid duration. Try something like `5s` or `1m 30s`."))
+        }
+    }
+}
+
+/// Accepts a whole number, or nothing at all.
+pub struct NumberValidator;
+impl Validator<String> for NumberValidator {
+    type Err = String;
+
+    fn validate(&mut self, s: &String) -> Result<(), Self::Err> {
+        if s.is_empty() || s.parse::<u64>().is_ok() {
+            Ok(())
+        } else {
+            Err(String::from("Invalid number."))
+        }
+    }
+}
+
+/// Accepts text whose `{placeholders}` are all ones a service template can fill in.
+/// Braces around anything but a plain name, or after a `$` like `${HOME}`, are left to the shell.
+pub struct TemplateValidator;
+impl Validator<String> for TemplateValidator {
+    type Err = String;
+
+    fn validate(&mut self, s: &String) -> Result<(), Self::Err> {
+        for (i, _) in s.match_indices('{') {
+            if s[..i].ends_with('$') {
+                continue;
+            }
+            let name = match s[i + 1..].split_once('}') {
+                Some((name, _)) => name,
+                None => continue,
+            };
+            let is_placeholder = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+            if is_placeholder && !TEMPLATE_PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "Unknown placeholder `{{{}}}`. Use {}.",
+                    name,
+                    TEMPLATE_PLACEHOLDERS.iter().map(|p| format!("{{{}}}", p)).collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(template: &str) -> Result<(), String> {
+        TemplateValidator.validate(&template.to_string())
+    }
+
+    #[test]
+    fn accepts_known_placeholders() {
+        assert!(check("{bin} -p {port} --data {working_dir}/{color}-{app}").is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert!(check("{bin} --name {name}").is_err());
+    }
+
+    #[test]
+    fn leaves_shell_braces_alone() {
+        assert!(check("${HOME}/bin/{app}").is_ok());
+        assert!(check("ps | awk '{print $1}'").is_ok());
+        assert!(check("sh -c 'f() { :; }; f'").is_ok());
+    }
+}
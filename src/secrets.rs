@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use anyhow::Result;
+use dialoguer::Password;
+use dialoguer::theme::ColorfulTheme;
+
+use crate::settings::settings;
+
+/// Secrets for a whole app (`dwbrite.com`) or one of its slots (`blue`),
+/// stored as a systemd EnvironmentFile readable only by root.
+/// They're kept apart from release and working dirs, so a release can't ship or leak them.
+/// A slot's are under a dir named after the app, so they can't be mistaken for another app's, like `blue-dwbrite.com`.
+pub(crate) fn secrets_path(app_name: &str, color: Option<&str>) -> String {
+    match color {
+        Some(color) => format!("{}/{}/{}.env", settings().secrets_dir, app_name, color),
+        None => format!("{}/{}.env", settings().secrets_dir, app_name),
+    }
+}
+
+fn read(path: &str) -> Result<BTreeMap<String, String>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(parse_env_file(&contents))
+}
+
+/// `KEY="value"` lines, as systemd reads an EnvironmentFile.
+pub(crate) fn parse_env_file(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), unquote(value.trim())))
+        .collect()
+}
+
+fn write(path: &str, secrets: &BTreeMap<String, String>) -> Result<()> {
+    // the secrets dir, and the app's dir for a slot's secrets
+    let mut dir = Path::new(path).parent();
+    while let Some(parent) = dir.filter(|d| d.starts_with(&settings().secrets_dir)) {
+        std::fs::create_dir_all(parent)?;
+        std::fs::set_permissions(parent, std::os::unix::fs::PermissionsExt::from_mode(0o700))?;
+        dir = parent.parent();
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    for (key, value) in secrets {
+        writeln!(file, "{}={}", key, quote(value))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn set(path: &str, key: &str, value: &str) -> Result<()> {
+    let mut secrets = read(path)?;
+    secrets.insert(key.to_string(), value.to_string());
+    write(path, &secrets)
+}
+
+pub(crate) fn unset(path: &str, key: &str) -> Result<bool> {
+    let mut secrets = read(path)?;
+    let removed = secrets.remove(key).is_some();
+    write(path, &secrets)?;
+    Ok(removed)
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+    let mut unquoted = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unquoted.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unquoted.push('\n'),
+            Some(c) => unquoted.push(c),
+            None => {}
+        }
+    }
+    unquoted
+}
+
+pub(crate) fn set_secret(app_name: String, key: String, value: Option<String>, color: Option<String>) {
+    if settings().needs_root() {
+        sudo::escalate_if_needed().expect("Higher privilege required to write secrets.");
+    }
+
+    // prompt rather than take the value as an argument, so it stays out of shell history
+    let value = value.unwrap_or_else(|| {
+        Password::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Value for {}", key))
+            .interact()
+            .unwrap()
+    });
+
+    let path = secrets_path(&app_name, color.as_deref());
+    match set(&path, &key, &value) {
+        Ok(_) => println!("Set {} in {}. Restart the services to pick it up.", key, path),
+        Err(e) => eprintln!("Failed to set {}: {}", key, e),
+    }
+}
+
+pub(crate) fn unset_secret(app_name: String, key: String, color: Option<String>) {
+    if settings().needs_root() {
+        sudo::escalate_if_needed().expect("Higher privilege required to write secrets.");
+    }
+
+    let path = secrets_path(&app_name, color.as_deref());
+    match unset(&path, &key) {
+        Ok(true) => println!("Removed {} from {}.", key, path),
+        Ok(false) => println!("{} isn't set in {}.", key, path),
+        Err(e) => eprintln!("Failed to remove {}: {}", key, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting_round_trips() {
+        for value in ["plain", "with spaces", "quote\"inside", "back\\slash", "two\nlines", "", "=", "trailing\\"] {
+            assert_eq!(unquote(&quote(value)), value);
+        }
+    }
+
+    #[test]
+    fn quotes_what_systemd_would_misread() {
+        assert_eq!(quote("a\"b\\c\nd"), r#""a\"b\\c\nd""#);
+    }
+
+    #[test]
+    fn parses_env_files() {
+        let env = parse_env_file("# a comment\nTOKEN=\"s3cr=t\"\n  PLAIN = bare \n\nMULTI=\"one\\ntwo\"\nnot a setting\n");
+        assert_eq!(env.len(), 3);
+        assert_eq!(env["TOKEN"], "s3cr=t");
+        assert_eq!(env["PLAIN"], "bare");
+        assert_eq!(env["MULTI"], "one\ntwo");
+    }
+
+    #[test]
+    fn slot_secrets_are_kept_apart_from_other_apps() {
+        // `blue-dwbrite.com` is as good an app name as any
+        assert_ne!(secrets_path("dwbrite.com", Some("blue")), secrets_path("blue-dwbrite.com", None));
+        assert_eq!(secrets_path("dwbrite.com", Some("blue")), format!("{}/dwbrite.com/blue.env", settings().secrets_dir));
+    }
+}
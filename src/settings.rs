@@ -0,0 +1,341 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::*;
+use serde_derive::{Deserialize, Serialize};
+
+pub(crate) const DEFAULT_CONFIG: &str = "/etc/dorc/dorc.toml";
+pub(crate) const CONFIG_ENV: &str = "DORC_CONFIG";
+pub(crate) const ROOT_ENV: &str = "DORC_ROOT";
+pub(crate) const USER_MODE_ENV: &str = "DORC_USER_MODE";
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// dorc's own settings, shared by every app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Settings {
+    // the default paths below are under this, e.g. to run dorc without root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) root: Option<String>,
+    // run services as whoever runs dorc, through `systemctl --user` or the built-in supervisor
+    pub(crate) user_mode: bool,
+
+    pub(crate) apps_dir: String,
+    pub(crate) secrets_dir: String,
+    pub(crate) service_data_dir: String, // where `dorc register` puts working dirs by default
+    pub(crate) state_dir: String,        // the release store, binaries, and the built-in supervisor's state
+    pub(crate) run_dir: String,          // the built-in supervisor's pid files
+    pub(crate) log_dir: String,          // output of services run by the built-in supervisor
+    pub(crate) unit_dir: String,         // where generated systemd units are written
+    pub(crate) fifo: String,             // commands for the daemon
+    pub(crate) handoff_socket: String,   // where a new daemon takes the listeners over
+    pub(crate) bind_address: IpAddr,     // for apps that don't set `listen_address`
+    pub(crate) port_range: [u16; 2],     // the first and last port dorc picks from for apps that leave theirs out
+    // takes the same commands as the FIFO over TCP, off unless set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) admin_address: Option<SocketAddr>,
+    // how long a daemon that was upgraded keeps the connections it had, before closing them
+    #[serde(with = "humantime_serde")]
+    pub(crate) drain_timeout: Duration,
+
+    pub(crate) log: LogSettings,
+    pub(crate) defaults: AppDefaults,
+
+    // where these were read from, if anywhere
+    #[serde(skip)]
+    pub(crate) path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct LogSettings {
+    pub(crate) level: Option<String>,       // e.g. `debug`; defaults to `info`, or `debug` in debug builds
+    pub(crate) destination: Option<String>, // a file to append to; defaults to stdout, which systemd sends to the journal
+    pub(crate) format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    #[default]
+    Plain,       // just the message, the journal adds the rest
+    Timestamped, // time, level and module
+    Json,        // one object per line
+}
+
+/// What apps get when they don't say otherwise.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct AppDefaults {
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub(crate) grace_period: Option<Duration>,
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub(crate) restart_sec: Option<Duration>,
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub(crate) timeout_stop_sec: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) connect_timeout: Duration, // for connections to services, after which they're counted as failed
+    #[serde(with = "humantime_serde")]
+    pub(crate) down_for: Duration, // how long an instance that refused a connection is left out of rotation
+}
+
+impl Default for AppDefaults {
+    fn default() -> Self {
+        AppDefaults {
+            grace_period: None,
+            restart_sec: None,
+            timeout_stop_sec: None,
+            connect_timeout: Duration::from_secs(5),
+            down_for: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::defaults(None, false)
+    }
+}
+
+/// `$var`, or `fallback` under the user's home directory, like the XDG base directories.
+fn xdg_dir(var: &str, fallback: &str) -> String {
+    std::env::var(var).unwrap_or_else(|_| format!("{}/{}", std::env::var("HOME").unwrap_or_default(), fallback))
+}
+
+fn under(root: Option<&str>, path: &str) -> String {
+    match root {
+        Some(root) => format!("{}{}", root.trim_end_matches('/'), path),
+        None => path.to_string(),
+    }
+}
+
+/// A config file's settings on top of `defaults`, table by table.
+fn merge(defaults: &mut toml::Value, file: toml::Value) {
+    match (defaults, file) {
+        (toml::Value::Table(defaults), toml::Value::Table(file)) => {
+            for (key, value) in file {
+                match defaults.get_mut(&key) {
+                    Some(default) => merge(default, value),
+                    None => {
+                        defaults.insert(key, value);
+                    }
+                }
+            }
+        }
+        (default, value) => *default = value,
+    }
+}
+
+impl Settings {
+    /// Where everything goes when it isn't configured: under `root` if there is one,
+    /// and where systemd looks for a user's units in user mode.
+    fn defaults(root: Option<&str>, user_mode: bool) -> Settings {
+        let under = |path: &str| under(root, path);
+
+        Settings {
+            root: root.map(str::to_string),
+            user_mode,
+            apps_dir: under("/etc/dorc/apps"),
+            secrets_dir: under("/etc/dorc/secrets"),
+            service_data_dir: under("/etc/dorc/service-data"),
+            state_dir: under("/var/lib/dorc"),
+            run_dir: under("/run/dorc"),
+            log_dir: under("/var/log/dorc"),
+            unit_dir: if user_mode {
+                format!("{}/systemd/user", xdg_dir("XDG_CONFIG_HOME", ".config"))
+            } else {
+                under("/etc/systemd/system")
+            },
+            fifo: under("/var/tmp/dorc-fifo"),
+            handoff_socket: under("/var/tmp/dorc-handoff.sock"),
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port_range: [20000, 29999],
+            admin_address: None,
+            drain_timeout: Duration::from_secs(10 * 60),
+            log: LogSettings::default(),
+            defaults: AppDefaults::default(),
+            path: None,
+        }
+    }
+
+    /// The config file to read: `path`, $DORC_CONFIG, the one under `root`,
+    /// the user's own if they aren't root and have one, or /etc/dorc/dorc.toml.
+    /// Returns whether it was asked for, rather than just where it usually is.
+    fn config_path(path: Option<PathBuf>, root: Option<&str>) -> (PathBuf, bool) {
+        if let Some(path) = path.or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from)) {
+            return (path, true);
+        }
+        if let Some(root) = root {
+            return (PathBuf::from(under(Some(root), DEFAULT_CONFIG)), false);
+        }
+
+        let user_config = PathBuf::from(format!("{}/dorc/dorc.toml", xdg_dir("XDG_CONFIG_HOME", ".config")));
+        // safe, geteuid can't fail
+        if unsafe { libc::geteuid() } != 0 && user_config.is_file() {
+            return (user_config, false);
+        }
+        (PathBuf::from(DEFAULT_CONFIG), false)
+    }
+
+    /// Reads the config file `config_path` picks, if there is one.
+    /// `root` (or $DORC_ROOT) takes precedence over the file's, and `user_mode` (or $DORC_USER_MODE) turns it on regardless.
+    pub(crate) fn load(path: Option<PathBuf>, root: Option<String>, user_mode: bool) -> Result<Settings> {
+        let root = root.or_else(|| std::env::var(ROOT_ENV).ok());
+        let user_mode = user_mode || std::env::var(USER_MODE_ENV).is_ok_and(|v| matches!(v.as_str(), "1" | "true" | "yes"));
+        let (path, explicit) = Settings::config_path(path, root.as_deref());
+
+        let file = match std::fs::read_to_string(&path) {
+            Ok(toml) => Some(toml::from_str::<toml::Value>(&toml).map_err(|e| anyhow!("{}: {}", path.display(), e))?),
+            Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => bail!("could not read {}: {}", path.display(), e),
+        };
+
+        // the defaults depend on these two, so they're picked out first
+        let setting = |key: &str| file.as_ref().and_then(|file| file.get(key)).cloned();
+        let user_mode = user_mode || setting("user_mode").and_then(|v| v.as_bool()).unwrap_or(false);
+        let root = root
+            .or_else(|| setting("root").and_then(|v| v.as_str().map(str::to_string)))
+            .or_else(|| user_mode.then(|| format!("{}/dorc", xdg_dir("XDG_DATA_HOME", ".local/share"))));
+
+        let mut value = toml::Value::try_from(Settings::defaults(root.as_deref(), user_mode))?;
+        if let Some(file) = file {
+            merge(&mut value, file);
+        }
+        // as do the root and user mode given on the command line
+        if let Some(table) = value.as_table_mut() {
+            if let Some(root) = &root {
+                table.insert("root".to_string(), toml::Value::String(root.clone()));
+            }
+            table.insert("user_mode".to_string(), toml::Value::Boolean(user_mode));
+        }
+
+        let mut settings: Settings = serde_path_to_error::deserialize(value)
+            .map_err(|e| anyhow!("{}: `{}`: {}", path.display(), e.path(), e.inner()))?;
+        if explicit || path.is_file() {
+            settings.path = Some(path);
+        }
+        Ok(settings)
+    }
+
+    /// Whether dorc's files are the system's own, so writing them takes root.
+    pub(crate) fn needs_root(&self) -> bool {
+        !self.user_mode && self.root.is_none()
+    }
+
+    /// Whether the systemd manager dorc talks to reads the units it writes. The system's
+    /// only reads /etc/systemd/system, not the copy under a root, so that takes user mode.
+    pub(crate) fn systemd_sees_units(&self) -> bool {
+        self.user_mode || self.root.is_none()
+    }
+
+    /// `path` under the root, if there is one.
+    pub(crate) fn under_root(&self, path: &str) -> String {
+        under(self.root.as_deref(), path)
+    }
+
+    pub(crate) fn app_path(&self, app_name: &str) -> PathBuf {
+        Path::new(&self.apps_dir).join(format!("{}.toml", app_name))
+    }
+
+    /// Every app's config, leaving out backups and anything else that isn't one.
+    pub(crate) fn app_paths(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&self.apps_dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "toml"))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Binaries that aren't part of their release, one per slot.
+    pub(crate) fn bin_dir(&self) -> String {
+        format!("{}/bin", self.state_dir)
+    }
+
+    /// One copy of each release file, which slots' working dirs can link to.
+    pub(crate) fn store_dir(&self) -> String {
+        format!("{}/store", self.state_dir)
+    }
+}
+
+/// For files dorc makes outside the dirs it creates anyway, which may not exist under a root.
+pub(crate) fn create_parent(path: &str) -> std::io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// Makes `settings` what `settings()` returns from now on. Only the first call counts.
+pub(crate) fn init(settings: Settings) {
+    let _ = SETTINGS.set(settings);
+}
+
+pub(crate) fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(test: &str, toml: &str, root: Option<&str>, user_mode: bool) -> Result<Settings> {
+        let path = std::env::temp_dir().join(format!("dorc-settings-{}-{}.toml", test, std::process::id()));
+        std::fs::write(&path, toml).unwrap();
+        let settings = Settings::load(Some(path.clone()), root.map(str::to_string), user_mode);
+        std::fs::remove_file(&path).unwrap();
+        settings
+    }
+
+    #[test]
+    fn the_file_overrides_defaults_one_setting_at_a_time() {
+        let settings = load("merge", r#"
+            root = "/srv/dorc"
+            log_dir = "/var/log/elsewhere"
+            [log]
+            format = "json"
+            [defaults]
+            connect_timeout = "2s"
+        "#, None, false).unwrap();
+
+        // paths it leaves out are under its root
+        assert_eq!(settings.apps_dir, "/srv/dorc/etc/dorc/apps");
+        assert_eq!(settings.log_dir, "/var/log/elsewhere");
+        assert_eq!(settings.log.format, LogFormat::Json);
+        assert_eq!(settings.log.level, None);
+        assert_eq!(settings.defaults.connect_timeout, Duration::from_secs(2));
+        assert_eq!(settings.defaults.down_for, AppDefaults::default().down_for);
+        assert!(settings.path.is_some());
+    }
+
+    #[test]
+    fn the_command_line_beats_the_file() {
+        let settings = load("precedence", "root = \"/srv/dorc\"\nuser_mode = false\n", Some("/opt/dorc/"), true).unwrap();
+        assert_eq!(settings.root.as_deref(), Some("/opt/dorc/"));
+        assert_eq!(settings.state_dir, "/opt/dorc/var/lib/dorc");
+        assert!(settings.user_mode);
+    }
+
+    #[test]
+    fn user_mode_keeps_files_in_the_users_home() {
+        let settings = load("user-mode", "user_mode = true\n", None, false).unwrap();
+        let root = format!("{}/dorc", xdg_dir("XDG_DATA_HOME", ".local/share"));
+        assert_eq!(settings.root.as_deref(), Some(root.as_str()));
+        assert_eq!(settings.fifo, format!("{}/var/tmp/dorc-fifo", root));
+        assert!(!settings.needs_root());
+        assert_eq!(settings.unit_dir, format!("{}/systemd/user", xdg_dir("XDG_CONFIG_HOME", ".config")));
+    }
+
+    #[test]
+    fn a_config_that_was_asked_for_has_to_be_there_and_valid() {
+        let missing = std::env::temp_dir().join(format!("dorc-settings-missing-{}.toml", std::process::id()));
+        assert!(Settings::load(Some(missing), None, false).is_err());
+
+        let error = load("invalid", "[defaults]\ndown_for = 10\n", None, false).unwrap_err().to_string();
+        assert!(error.contains("defaults.down_for"), "{}", error);
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::os::unix::io::OwnedFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use log::*;
+
+use crate::App;
+use crate::registration::types::Service;
+use crate::secrets::parse_env_file;
+use crate::settings::settings;
+use crate::supervisor::{Status, Supervisor};
+
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const DEFAULT_RESTART_SEC: Duration = Duration::from_secs(1);
+const DEFAULT_TIMEOUT_STOP_SEC: Duration = Duration::from_secs(10);
+
+/// Runs services as children of whichever dorc process starts them, tracked by pid files.
+/// The daemon's `Watchdog` restarts them when they exit.
+pub(crate) struct Builtin;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Stopped,
+    Running(i32),
+    Exited(Option<i32>), // exit code, if we saw it
+}
+
+// pids don't survive a reboot, so they're kept on tmpfs
+fn run_dir() -> String {
+    format!("{}/supervisor", settings().run_dir)
+}
+
+fn state_dir() -> String {
+    format!("{}/supervisor", settings().state_dir)
+}
+
+fn pid_path(name: &str) -> String {
+    format!("{}/{}.pid", run_dir(), name)
+}
+
+fn exit_path(name: &str) -> String {
+    format!("{}/{}.exit", run_dir(), name)
+}
+
+fn enabled_path(name: &str) -> String {
+    format!("{}/{}.enabled", state_dir(), name)
+}
+
+fn definition_path(name: &str) -> String {
+    format!("{}/{}.service", state_dir(), name)
+}
+
+/// Where a service's stdout and stderr end up.
+pub(crate) fn log_path(name: &str) -> String {
+    format!("{}/{}.log", settings().log_dir, name)
+}
+
+fn state(name: &str) -> Result<State> {
+    let pid: i32 = match std::fs::read_to_string(pid_path(name)) {
+        Ok(pid) => pid.trim().parse()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(State::Stopped),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut status = 0;
+    match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+        0 => Ok(State::Running(pid)),
+        reaped if reaped == pid => {
+            // like a shell, 128 + the signal for services that were killed
+            let code = if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { 128 + libc::WTERMSIG(status) };
+            std::fs::write(exit_path(name), code.to_string())?;
+            Ok(State::Exited(Some(code)))
+        }
+        // not our child, e.g. started by `dorc register` or a previous daemon
+        _ => {
+            let alive = unsafe { libc::kill(pid, 0) } == 0
+                || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+            if alive {
+                Ok(State::Running(pid))
+            } else {
+                let code = std::fs::read_to_string(exit_path(name)).ok().and_then(|c| c.trim().parse().ok());
+                Ok(State::Exited(code))
+            }
+        }
+    }
+}
+
+fn forget(name: &str) {
+    let _ = std::fs::remove_file(pid_path(name));
+    let _ = std::fs::remove_file(exit_path(name));
+}
+
+fn uid_gid(user: &str, group: Option<&str>) -> Result<(u32, u32)> {
+    let c_user = CString::new(user)?;
+    let passwd = unsafe { libc::getpwnam(c_user.as_ptr()) };
+    if passwd.is_null() {
+        bail!("no such user {}", user);
+    }
+    let (uid, mut gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+
+    if let Some(group) = group {
+        let c_group = CString::new(group)?;
+        let grp = unsafe { libc::getgrnam(c_group.as_ptr()) };
+        if grp.is_null() {
+            bail!("no such group {}", group);
+        }
+        gid = unsafe { (*grp).gr_gid };
+    }
+
+    Ok((uid, gid))
+}
+
+/// The environment systemd would give the service: its Environment= then its EnvironmentFile=s.
+pub(crate) fn environment(app: &App, service: &Service) -> Result<BTreeMap<String, String>> {
+    let mut env: BTreeMap<String, String> = service.environment(app).into_iter().collect();
+    env.insert("PATH".to_string(), DEFAULT_PATH.to_string());
+
+    for file in service.environment_files(app) {
+        let (path, optional) = match file.strip_prefix('-') {
+            Some(path) => (path.to_string(), true),
+            None => (file, false),
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => env.extend(parse_env_file(&contents)),
+            Err(e) if optional && e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("could not read {}: {}", path, e)),
+        }
+    }
+
+    Ok(env)
+}
+
+/// A shell running `line` the way the service itself runs: in its working dir, with its environment, as its user.
+pub(crate) fn command(app: &App, service: &Service, line: &str) -> Result<Command> {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(line)
+        .current_dir(&service.working_dir)
+        .env_clear()
+        .envs(environment(app, service)?)
+        .stdin(Stdio::null());
+
+    if let Some(user) = &app.systemd.user {
+        let (uid, gid) = uid_gid(user, app.systemd.group.as_deref())?;
+        command.uid(uid).gid(gid);
+    }
+
+    Ok(command)
+}
+
+fn spawn(app: &App, service: &Service) -> Result<i32> {
+    let name = &service.qualified_name;
+    std::fs::create_dir_all(run_dir())?;
+    std::fs::create_dir_all(&settings().log_dir)?;
+
+    // `exec` so the pid we track is the service's, not the shell's
+    let mut command = command(app, service, &format!("exec {}", service.on_start))?;
+    let log = start_logger(name)?;
+    command.stdout(log.try_clone()?).stderr(log);
+    unsafe {
+        // its own process group, so stopping it reaches anything it forks
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+
+    let child = command.spawn().with_context(|| format!("could not start {}", name))?;
+    let pid = child.id() as i32;
+
+    let _ = std::fs::remove_file(exit_path(name));
+    std::fs::write(pid_path(name), pid.to_string())?;
+    Ok(pid)
+}
+
+/// Starts a `dorc write-log` for `name`'s output, returning the pipe to it.
+/// It exits once the service and anything it forked have closed their end.
+fn start_logger(name: &str) -> Result<OwnedFd> {
+    let mut command = Command::new(crate::own_exe()?);
+    command.args(["write-log", &log_path(name)]).stdin(Stdio::piped());
+    unsafe {
+        // out of the daemon's process group, so it outlives a handoff like the service does
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+
+    let mut logger = command.spawn().with_context(|| format!("could not start {}'s logger", name))?;
+    let pipe = logger.stdin.take().unwrap();
+    std::thread::spawn(move || logger.wait());
+    Ok(pipe.into())
+}
+
+/// Copies stdin to the end of `path` a line at a time, each line after the time it was read.
+pub(crate) fn write_log(path: &str) -> Result<()> {
+    let mut log = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut stdin = std::io::stdin().lock();
+    let mut line = vec![];
+    while stdin.read_until(b'\n', &mut line)? > 0 {
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        let time = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, false);
+        // one write per line, so a reader never sees half of one
+        log.write_all(&[time.as_bytes(), b" ", &line].concat())?;
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Splits a line `write_log` wrote into its time and the service's output.
+/// Lines from before dorc timestamped them have no time.
+pub(crate) fn parse_log_line(line: &str) -> (Option<chrono::DateTime<chrono::FixedOffset>>, &str) {
+    line.split_once(' ')
+        .and_then(|(time, rest)| Some((chrono::DateTime::parse_from_rfc3339(time).ok()?, rest)))
+        .map_or((None, line), |(time, rest)| (Some(time), rest))
+}
+
+/// Runs `on_reload` or `on_stop` commands to completion, with $MAINPID set like systemd does.
+fn run_commands(app: &App, service: &Service, commands: &[String], pid: i32) -> Result<()> {
+    for line in commands {
+        let status = command(app, service, line)?.env("MAINPID", pid.to_string()).status()?;
+        if !status.success() {
+            bail!("`{}` failed with {}", line, status);
+        }
+    }
+    Ok(())
+}
+
+fn wait_for_exit(name: &str, timeout: Duration) -> Result<bool> {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if !matches!(state(name)?, State::Running(_)) {
+            return Ok(true);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(false)
+}
+
+impl Supervisor for Builtin {
+    fn install(&self, app: &App, service: &Service) -> Result<bool> {
+        // the unit we'd give systemd captures everything that affects how the service runs
+        let definition = service.to_unit_file(app);
+        let path = definition_path(&service.qualified_name);
+        if std::fs::read_to_string(&path).ok().as_deref() == Some(definition.as_str()) {
+            return Ok(false);
+        }
+
+        std::fs::create_dir_all(state_dir())?;
+        std::fs::write(path, definition)?;
+        Ok(true)
+    }
+
+    fn start(&self, app: &App, service: &Service) -> Result<()> {
+        if let State::Running(_) = state(&service.qualified_name)? {
+            return Ok(());
+        }
+
+        let pid = spawn(app, service)?;
+        info!("Started {} (pid {})", service.qualified_name, pid);
+        Ok(())
+    }
+
+    fn stop(&self, app: &App, service: &Service) -> Result<()> {
+        let name = &service.qualified_name;
+        let pid = match state(name)? {
+            State::Running(pid) => pid,
+            _ => {
+                forget(name);
+                return Ok(());
+            }
+        };
+
+        // waiting on a runtime thread would hold up the daemon's other tasks
+        tokio::task::block_in_place(|| -> Result<()> {
+            // like systemd, whatever `on_stop` leaves running is sent SIGTERM, then SIGKILL
+            if let Some(commands) = &service.on_stop {
+                if let Err(e) = run_commands(app, service, commands, pid) {
+                    warn!("on_stop for {} failed: {}", name, e);
+                }
+            }
+            if matches!(state(name)?, State::Running(_)) {
+                unsafe {
+                    libc::kill(-pid, libc::SIGTERM);
+                }
+            }
+
+            let timeout = app.systemd.timeout_stop_sec().unwrap_or(DEFAULT_TIMEOUT_STOP_SEC);
+            if !wait_for_exit(name, timeout)? {
+                warn!("{} didn't stop within {}, killing it", name, humantime::format_duration(timeout));
+                unsafe {
+                    libc::kill(-pid, libc::SIGKILL);
+                }
+                wait_for_exit(name, Duration::from_secs(5))?;
+            }
+            Ok(())
+        })?;
+
+        forget(name);
+        Ok(())
+    }
+
+    fn reload(&self, app: &App, service: &Service) -> Result<()> {
+        let pid = match state(&service.qualified_name)? {
+            State::Running(pid) => pid,
+            _ => bail!("{} is not running", service.qualified_name),
+        };
+
+        match &service.on_reload {
+            Some(commands) => run_commands(app, service, commands, pid),
+            None => bail!("{} has no on_reload commands", service.qualified_name),
+        }
+    }
+
+    fn status(&self, service: &Service) -> Result<Status> {
+        Ok(match state(&service.qualified_name)? {
+            State::Running(_) => Status::Active,
+            State::Stopped | State::Exited(Some(0)) => Status::Inactive,
+            State::Exited(_) => Status::Failed,
+        })
+    }
+
+    fn enable(&self, service: &Service) -> Result<()> {
+        std::fs::create_dir_all(state_dir())?;
+        std::fs::write(enabled_path(&service.qualified_name), "")?;
+        Ok(())
+    }
+
+    fn remove(&self, service: &Service) -> Result<()> {
+        for path in [definition_path(&service.qualified_name), enabled_path(&service.qualified_name)] {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts an app's enabled services that aren't running yet, e.g. after a reboot.
+pub(crate) fn start_enabled(app: &App) {
+    for service in &app.instances() {
+        let name = &service.qualified_name;
+        if !std::path::Path::new(&enabled_path(name)).exists() {
+            continue;
+        }
+
+        match state(name) {
+            Ok(State::Stopped) => {
+                if let Err(e) = Builtin.start(app, service) {
+                    error!("Failed to start {}: {}", name, e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Could not check on {}: {}", name, e),
+        }
+    }
+}
+
+/// Restarts services that exited on their own, following the app's `restart` policy.
+#[derive(Default)]
+pub(crate) struct Watchdog {
+    exited_at: HashMap<String, Instant>,
+}
+
+impl Watchdog {
+    pub(crate) fn check(&mut self, app: &App) {
+        for service in &app.instances() {
+            let name = &service.qualified_name;
+            let code = match state(name) {
+                Ok(State::Exited(code)) => code,
+                Ok(_) => {
+                    self.exited_at.remove(name);
+                    continue;
+                }
+                Err(e) => {
+                    error!("Could not check on {}: {}", name, e);
+                    continue;
+                }
+            };
+
+            let exited_at = *self.exited_at.entry(name.clone()).or_insert_with(|| {
+                match code {
+                    Some(code) => warn!("{} exited with code {}", name, code),
+                    None => warn!("{} exited", name),
+                }
+                Instant::now()
+            });
+
+            let restart = match app.systemd.restart.as_deref() {
+                Some("always") => true,
+                Some("on-failure") | Some("on-abnormal") => code != Some(0),
+                _ => false,
+            };
+            if !restart || exited_at.elapsed() < app.systemd.restart_sec().unwrap_or(DEFAULT_RESTART_SEC) {
+                continue;
+            }
+
+            self.exited_at.remove(name);
+            if let Err(e) = Builtin.start(app, service) {
+                error!("Failed to restart {}: {}", name, e);
+            }
+        }
+    }
+}
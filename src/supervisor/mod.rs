@@ -0,0 +1,46 @@
+pub(crate) mod builtin;
+pub(crate) mod systemd;
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::App;
+use crate::registration::types::Service;
+
+/// What runs an app's services.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SupervisorKind {
+    #[default]
+    Systemd,
+    Builtin, // the dorc daemon spawns and restarts the services itself
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Status {
+    Active,
+    Inactive,
+    Failed,
+}
+
+pub(crate) trait Supervisor {
+    /// Writes out how `service` should be run, returning whether that changed.
+    fn install(&self, app: &App, service: &Service) -> Result<bool>;
+    fn start(&self, app: &App, service: &Service) -> Result<()>;
+    fn stop(&self, app: &App, service: &Service) -> Result<()>;
+    fn reload(&self, app: &App, service: &Service) -> Result<()>;
+    fn status(&self, service: &Service) -> Result<Status>;
+    /// Start `service` whenever the machine (or for the built-in supervisor, the daemon) starts.
+    fn enable(&self, service: &Service) -> Result<()>;
+    /// Deletes what `install` and `enable` wrote, for a stopped service that's gone from its app.
+    fn remove(&self, service: &Service) -> Result<()>;
+}
+
+impl SupervisorKind {
+    pub(crate) fn supervisor(&self) -> Box<dyn Supervisor> {
+        match self {
+            SupervisorKind::Systemd => Box::new(systemd::Systemd),
+            SupervisorKind::Builtin => Box::new(builtin::Builtin),
+        }
+    }
+}
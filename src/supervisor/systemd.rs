@@ -0,0 +1,220 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use futures::StreamExt;
+use zbus::blocking::Connection;
+use zbus::zvariant::OwnedObjectPath;
+
+use crate::App;
+use crate::registration::types::Service;
+use crate::settings::settings;
+use crate::supervisor::{Status, Supervisor};
+
+/// How long a job may take before dorc stops waiting for it. Well past systemd's own
+/// default start and stop timeouts, so it only catches jobs that are stuck.
+const JOB_TIMEOUT: Duration = Duration::from_secs(300);
+
+// shared by every call, rather than connecting and subscribing each time
+static SYSTEMD1: Mutex<Option<Systemd1>> = Mutex::new(None);
+
+/// What `EnableUnitFiles` changed: the kind of change, the link, and where it points.
+type UnitFileChanges = Vec<(String, String, String)>;
+
+#[zbus::proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait Manager {
+    fn start_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn reload_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn load_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+    fn enable_unit_files(&self, files: &[&str], runtime: bool, force: bool) -> zbus::Result<(bool, UnitFileChanges)>;
+    fn disable_unit_files(&self, files: &[&str], runtime: bool) -> zbus::Result<UnitFileChanges>;
+    fn reload(&self) -> zbus::Result<()>;
+    fn subscribe(&self) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn job_removed(&self, id: u32, job: OwnedObjectPath, unit: String, result: String) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(interface = "org.freedesktop.systemd1.Unit", default_service = "org.freedesktop.systemd1")]
+trait Unit {
+    #[zbus(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+}
+
+/// systemd's manager, over the system bus, or the user's own in user mode.
+#[derive(Clone)]
+pub(crate) struct Systemd1 {
+    connection: Connection,
+    manager: ManagerProxyBlocking<'static>,
+}
+
+/// Units are addressed by their full name over D-Bus, `dorc` alone won't do.
+fn unit_name(name: &str) -> String {
+    if name.ends_with(".service") || name.ends_with(".socket") {
+        name.to_string()
+    } else {
+        format!("{}.service", name)
+    }
+}
+
+impl Systemd1 {
+    /// The connection made by the first call, or a new one if that failed.
+    pub(crate) fn connect() -> Result<Self> {
+        let mut shared = SYSTEMD1.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(systemd1) = shared.as_ref() {
+            return Ok(systemd1.clone());
+        }
+
+        if !settings().systemd_sees_units() {
+            bail!("the system's systemd doesn't read units under {}, use user mode or the built-in supervisor",
+                settings().root.as_deref().unwrap_or_default());
+        }
+
+        let connection = if settings().user_mode { Connection::session() } else { Connection::system() }
+            .map_err(|e| anyhow!("could not connect to systemd over D-Bus: {}", e))?;
+        let manager = ManagerProxyBlocking::new(&connection)?;
+        // systemd only tells subscribers when jobs finish
+        manager.subscribe()?;
+
+        let systemd1 = Systemd1 { connection, manager };
+        *shared = Some(systemd1.clone());
+        Ok(systemd1)
+    }
+
+    /// Queues a job and waits for systemd to finish it, failing unless it's `done`.
+    fn run_job<F>(&self, verb: &str, unit: &str, queue: F) -> Result<()>
+    where
+        F: FnOnce(&ManagerProxyBlocking<'static>, &str) -> zbus::Result<OwnedObjectPath>,
+    {
+        let unit = unit_name(unit);
+        let wait = async {
+            // listen before queueing, so a quick job can't finish unseen
+            let manager = ManagerProxy::from(self.manager.inner().inner().clone());
+            let mut removed = manager.receive_job_removed().await?;
+            let job = queue(&self.manager, &unit).map_err(|e| anyhow!("could not {} {}: {}", verb, unit, e))?;
+
+            // dropping the signals when this times out stops listening for them
+            let finished = tokio::time::timeout(JOB_TIMEOUT, async {
+                while let Some(signal) = removed.next().await {
+                    match signal.args() {
+                        Ok(args) if args.job() == &job => return Some(args.result().to_string()),
+                        _ => {}
+                    }
+                }
+                None
+            }).await;
+            Ok::<_, anyhow::Error>((job, finished))
+        };
+
+        match tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(wait))? {
+            (_, Ok(Some(result))) if result == "done" => Ok(()),
+            (_, Ok(Some(result))) => Err(anyhow!("failed to {} {}: job {} (see `journalctl -u {}`)", verb, unit, result, unit)),
+            (_, Ok(None)) => bail!("lost track of the {} job for {}", verb, unit),
+            (job, Err(_)) => bail!(
+                "gave up waiting to {} {} after {}, its job {} may still be running",
+                verb, unit, humantime::format_duration(JOB_TIMEOUT), job.as_str()
+            ),
+        }
+    }
+
+    pub(crate) fn start(&self, unit: &str) -> Result<()> {
+        self.run_job("start", unit, |m, unit| m.start_unit(unit, "replace"))
+    }
+
+    pub(crate) fn stop(&self, unit: &str) -> Result<()> {
+        self.run_job("stop", unit, |m, unit| m.stop_unit(unit, "replace"))
+    }
+
+    pub(crate) fn reload(&self, unit: &str) -> Result<()> {
+        self.run_job("reload", unit, |m, unit| m.reload_unit(unit, "replace"))
+    }
+
+    pub(crate) fn enable(&self, unit: &str) -> Result<()> {
+        let unit = unit_name(unit);
+        self.manager.enable_unit_files(&[&unit], false, true)
+            .map_err(|e| anyhow!("could not enable {}: {}", unit, e))?;
+        self.daemon_reload()
+    }
+
+    pub(crate) fn disable(&self, unit: &str) -> Result<()> {
+        let unit = unit_name(unit);
+        self.manager.disable_unit_files(&[&unit], false)
+            .map_err(|e| anyhow!("could not disable {}: {}", unit, e))?;
+        Ok(())
+    }
+
+    pub(crate) fn daemon_reload(&self) -> Result<()> {
+        self.manager.reload().map_err(|e| anyhow!("could not reload systemd's units: {}", e))?;
+        Ok(())
+    }
+
+    /// `active`, `inactive`, `failed` and so on; `inactive` for units systemd doesn't know.
+    pub(crate) fn active_state(&self, unit: &str) -> Result<String> {
+        let unit = unit_name(unit);
+        let path = self.manager.load_unit(&unit).map_err(|e| anyhow!("could not load {}: {}", unit, e))?;
+        let proxy = UnitProxyBlocking::builder(&self.connection).path(path)?.build()?;
+        Ok(proxy.active_state()?)
+    }
+}
+
+/// Runs services as systemd units.
+pub(crate) struct Systemd;
+
+impl Supervisor for Systemd {
+    fn install(&self, app: &App, service: &Service) -> Result<bool> {
+        let unit = service.to_unit_file(app);
+        if std::fs::read_to_string(service.unit_path()).ok().as_deref() == Some(unit.as_str()) {
+            return Ok(false);
+        }
+
+        // ~/.config/systemd/user may not exist yet
+        std::fs::create_dir_all(&settings().unit_dir)?;
+        std::fs::write(service.unit_path(), unit)?;
+        Systemd1::connect()?.daemon_reload()?;
+        Ok(true)
+    }
+
+    fn start(&self, _app: &App, service: &Service) -> Result<()> {
+        Systemd1::connect()?.start(&service.qualified_name)
+    }
+
+    fn stop(&self, _app: &App, service: &Service) -> Result<()> {
+        let systemd = Systemd1::connect()?;
+        // stopping a unit that doesn't exist yet is an error, but there's nothing to do
+        if systemd.active_state(&service.qualified_name)? == "inactive" {
+            return Ok(());
+        }
+        systemd.stop(&service.qualified_name)
+    }
+
+    fn reload(&self, _app: &App, service: &Service) -> Result<()> {
+        Systemd1::connect()?.reload(&service.qualified_name)
+    }
+
+    fn status(&self, service: &Service) -> Result<Status> {
+        Ok(match Systemd1::connect()?.active_state(&service.qualified_name)?.as_str() {
+            "active" | "reloading" | "activating" => Status::Active,
+            "failed" => Status::Failed,
+            _ => Status::Inactive,
+        })
+    }
+
+    fn enable(&self, service: &Service) -> Result<()> {
+        Systemd1::connect()?.enable(&service.qualified_name)
+    }
+
+    fn remove(&self, service: &Service) -> Result<()> {
+        let systemd = Systemd1::connect()?;
+        systemd.disable(&service.qualified_name)?;
+        match std::fs::remove_file(service.unit_path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        systemd.daemon_reload()
+    }
+}
@@ -0,0 +1,455 @@
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use dialoguer::console::style;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::App;
+use crate::settings::settings;
+use crate::templates::TEMPLATE_SUFFIX;
+
+/// How a release is copied into a slot's working dir.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncConfig {
+    #[serde(default)]
+    pub(crate) mode: SyncMode,
+    // relative to the working dir, e.g. `data/`; never deleted by a mirror sync
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) preserve: Vec<String>,
+    // link files from a store instead of copying them; a service that writes to a release file changes it for every slot
+    #[serde(default)]
+    pub(crate) hardlink: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    #[default]
+    Copy,   // overwrite what's in the release, leave everything else
+    Mirror, // also delete whatever isn't in the release
+}
+
+impl SyncConfig {
+    fn preserved(&self) -> Vec<PathBuf> {
+        self.preserve.iter().map(|p| PathBuf::from(p.trim_end_matches('/'))).collect()
+    }
+
+    /// What a mirror sync deletes from `working_dir`: everything that isn't in `release_dir` or preserved.
+    /// Directories are listed instead of their contents.
+    pub(crate) fn stale_paths(&self, release_dir: &str, working_dir: &str) -> Result<Vec<PathBuf>> {
+        let mut stale = vec![];
+        collect_stale(Path::new(release_dir), Path::new(working_dir), Path::new(""), &self.preserved(), &mut stale)?;
+        Ok(stale)
+    }
+
+    /// Deletes what `stale_paths` lists, if this is a mirror sync.
+    pub(crate) fn prune(&self, release_dir: &str, working_dir: &str) -> Result<()> {
+        if self.mode != SyncMode::Mirror {
+            return Ok(());
+        }
+
+        let stale = self.stale_paths(release_dir, working_dir)?;
+        for path in &stale {
+            debug!("deleting {:?}, it isn't in the release", path);
+            if path.symlink_metadata()?.is_dir() {
+                std::fs::remove_dir_all(path)?;
+            } else {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        if !stale.is_empty() {
+            info!("deleted {} paths from {} that aren't in the release", stale.len(), working_dir);
+        }
+        Ok(())
+    }
+}
+
+/// What copying a release did, and how long it took.
+#[derive(Debug, Default)]
+pub(crate) struct SyncStats {
+    copied: usize,
+    bytes_copied: u64,
+    linked: usize,
+    unlinked: usize, // copied instead, since they're on another filesystem than the store
+    unchanged: usize,
+    elapsed: Duration,
+}
+
+impl fmt::Display for SyncStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "copied {} files ({} bytes), linked {}, left {} unchanged in {:?}",
+            self.copied, self.bytes_copied, self.linked, self.unchanged, self.elapsed
+        )
+    }
+}
+
+impl SyncConfig {
+    /// Copies whatever changed in `release_dir` since the last release into `working_dir`.
+    pub(crate) fn copy(&self, app_name: &str, release_dir: &str, working_dir: &str) -> Result<SyncStats> {
+        let started = Instant::now();
+        let mut stats = SyncStats::default();
+
+        let store = if self.hardlink {
+            let store = Path::new(&settings().store_dir()).join(app_name);
+            std::fs::create_dir_all(&store)?;
+            std::fs::create_dir_all(working_dir)?;
+            // links can't cross filesystems, and a store no working dir links to would be emptied and refilled every release
+            if store.metadata()?.dev() == Path::new(working_dir).metadata()?.dev() {
+                Some(store)
+            } else {
+                warn!("{} isn't on the same filesystem as {}, copying the release instead of linking it", settings().store_dir(), working_dir);
+                None
+            }
+        } else {
+            None
+        };
+
+        copy_dir(Path::new(release_dir), Path::new(working_dir), store.as_deref(), &mut stats)?;
+
+        // unless some files couldn't be linked, whose entries would look unused too
+        if let (Some(store), 0) = (&store, stats.unlinked) {
+            remove_unlinked(store)?;
+        }
+
+        stats.elapsed = started.elapsed();
+        Ok(stats)
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path, store: Option<&Path>, stats: &mut SyncStats) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let (from, to) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            copy_dir(&from, &to, store, stats)?;
+        } else if entry.file_name().to_string_lossy().ends_with(TEMPLATE_SUFFIX) {
+            // rendered for each slot instead, see `templates::render_all`
+            continue;
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&from)?;
+            if std::fs::read_link(&to).ok().as_ref() == Some(&target) {
+                stats.unchanged += 1;
+                continue;
+            }
+            remove_if_exists(&to)?;
+            std::os::unix::fs::symlink(target, &to)?;
+            stats.copied += 1;
+        } else {
+            let metadata = entry.metadata()?;
+            match store {
+                Some(store) => link_file(&from, &to, &metadata, store, stats)?,
+                None => copy_file(&from, &to, &metadata, stats)?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies are given the release file's mtime, so the next sync can tell whether it changed.
+fn copy_file(from: &Path, to: &Path, metadata: &Metadata, stats: &mut SyncStats) -> Result<()> {
+    if let Ok(existing) = to.symlink_metadata() {
+        if existing.is_file() && existing.len() == metadata.len() && existing.modified()? == metadata.modified()?
+            && existing.permissions().mode() == metadata.permissions().mode()
+        {
+            stats.unchanged += 1;
+            return Ok(());
+        }
+    }
+
+    // rather than writing through, which would change a linked file everywhere, or fail on a running binary
+    remove_if_exists(to)?;
+    std::fs::copy(from, to)?;
+    // opened for reading, since the copy may be read-only
+    File::open(to)?.set_modified(metadata.modified()?)?;
+
+    stats.copied += 1;
+    stats.bytes_copied += metadata.len();
+    Ok(())
+}
+
+/// Names a release file's entry in the store. A changed file gets a new entry,
+/// and the same file the same one, even after dorc is rebuilt with another Rust.
+fn store_key(from: &Path, metadata: &Metadata) -> String {
+    // FNV-1a, 64 bit
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let fields = [metadata.len(), metadata.mtime() as u64, metadata.mtime_nsec() as u64, metadata.mode() as u64];
+    let bytes = from.as_os_str().as_bytes().iter().copied().chain(fields.iter().flat_map(|f| f.to_le_bytes()));
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn link_file(from: &Path, to: &Path, metadata: &Metadata, store: &Path, stats: &mut SyncStats) -> Result<()> {
+    let stored = store.join(store_key(from, metadata));
+
+    if !stored.exists() {
+        let partial = stored.with_extension("partial");
+        copy_file(from, &partial, metadata, stats)?;
+        std::fs::rename(&partial, &stored)?;
+    }
+
+    let stored_metadata = stored.metadata()?;
+    if let Ok(existing) = to.symlink_metadata() {
+        if existing.dev() == stored_metadata.dev() && existing.ino() == stored_metadata.ino() {
+            stats.unchanged += 1;
+            return Ok(());
+        }
+    }
+
+    remove_if_exists(to)?;
+    match std::fs::hard_link(&stored, to) {
+        Ok(_) => stats.linked += 1,
+        // the store has to be on the working dir's filesystem
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            warn!("could not link {:?} from {}, copying it instead | {}", to, settings().store_dir(), e);
+            copy_file(from, to, metadata, stats)?;
+            stats.unlinked += 1;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Deletes the store's files that no working dir links to anymore.
+fn remove_unlinked(store: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(store)? {
+        let entry = entry?;
+        if entry.metadata()?.nlink() == 1 {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn collect_stale(release: &Path, working: &Path, relative: &Path, preserved: &[PathBuf], stale: &mut Vec<PathBuf>) -> Result<()> {
+    let dir = working.join(relative);
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        if preserved.iter().any(|p| relative.starts_with(p)) {
+            continue;
+        }
+
+        let is_dir = entry.file_type()?.is_dir();
+        let in_release = release.join(&relative).symlink_metadata().ok().map(|m| m.is_dir())
+            .or_else(|| {
+                // rendered from a template
+                let template = format!("{}{}", release.join(&relative).display(), TEMPLATE_SUFFIX);
+                Path::new(&template).is_file().then_some(false)
+            });
+        // a preserved path inside this dir has to be kept, so only its other contents can go
+        let holds_preserved = preserved.iter().any(|p| p.starts_with(&relative));
+
+        match in_release {
+            Some(release_is_dir) if release_is_dir == is_dir => {
+                if is_dir {
+                    collect_stale(release, working, &relative, preserved, stale)?;
+                }
+            }
+            _ if is_dir && holds_preserved => collect_stale(release, working, &relative, preserved, stale)?,
+            _ => stale.push(working.join(&relative)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints what the next release would delete from a slot, without deleting anything.
+pub(crate) fn dry_run(app_name: String, color: Option<String>) {
+    if let Err(e) = print_dry_run(&app_name, color.as_deref()) {
+        eprintln!("Failed to check {}'s working dirs: {}", app_name, e);
+    }
+}
+
+fn print_dry_run(app_name: &str, color: Option<&str>) -> Result<()> {
+    let app = App::load(settings().app_path(app_name))?;
+    let service = match color {
+        Some(color) => app.slot(color).ok_or_else(|| anyhow!("{} has no slot `{}`", app_name, color))?,
+        // where the next release goes
+        None => match app.ingest_slot() {
+            Some(slot) => &app.slots[slot],
+            None => bail!("{} has no inactive slot to copy a release to", app_name),
+        },
+    };
+
+    let stale = app.sync.stale_paths(&app.release_dir, &service.working_dir)?;
+    println!(
+        "{} {} in {} {} in {}{}",
+        stale.len(),
+        if stale.len() == 1 { "path" } else { "paths" },
+        style(&service.working_dir).bold(),
+        if stale.len() == 1 { "isn't" } else { "aren't" },
+        style(&app.release_dir).bold(),
+        if stale.is_empty() { "." } else { ":" }
+    );
+    for path in &stale {
+        println!("    {} {}", style("-").red(), path.display());
+    }
+
+    if app.sync.mode != SyncMode::Mirror && !stale.is_empty() {
+        println!("They're kept, since {}'s sync mode isn't `mirror`.", app_name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh release and working dir with `files` in each; names ending in `/` are dirs.
+    fn dirs(test: &str, release: &[&str], working: &[&str]) -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("dorc-sync-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (dir, files) in [("release", release), ("working", working)] {
+            for file in files {
+                let path = root.join(dir).join(file);
+                if file.ends_with('/') {
+                    std::fs::create_dir_all(path).unwrap();
+                } else {
+                    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    std::fs::write(path, "").unwrap();
+                }
+            }
+        }
+        (root.join("release"), root.join("working"))
+    }
+
+    fn stale(release: &Path, working: &Path, preserved: &[&str]) -> Vec<String> {
+        let preserved: Vec<PathBuf> = preserved.iter().map(PathBuf::from).collect();
+        let mut stale = vec![];
+        collect_stale(release, working, Path::new(""), &preserved, &mut stale).unwrap();
+        std::fs::remove_dir_all(release.parent().unwrap()).unwrap();
+
+        let mut stale: Vec<String> = stale.iter()
+            .map(|p| p.strip_prefix(working).unwrap().display().to_string())
+            .collect();
+        stale.sort();
+        stale
+    }
+
+    #[test]
+    fn only_what_the_release_lacks_is_stale() {
+        let (release, working) = dirs("lacks", &["app", "static/a.css"], &["app", "static/a.css", "static/old.css", "old/x"]);
+        assert_eq!(stale(&release, &working, &[]), vec!["old", "static/old.css"]);
+    }
+
+    #[test]
+    fn keeps_preserved_paths_and_rendered_templates() {
+        let (release, working) = dirs(
+            "preserved",
+            &["app", &format!("config.toml{}", TEMPLATE_SUFFIX)],
+            &["app", "config.toml", "data/db", "data/cache/x", "logs/today"],
+        );
+        assert_eq!(stale(&release, &working, &["data/db", "logs"]), vec!["data/cache"]);
+    }
+
+    #[test]
+    fn a_file_where_the_release_has_a_dir_is_stale() {
+        let (release, working) = dirs("kind", &["assets/"], &["assets"]);
+        assert_eq!(stale(&release, &working, &[]), vec!["assets"]);
+    }
+
+    fn config(mode: SyncMode, preserve: &[&str]) -> SyncConfig {
+        SyncConfig { mode, preserve: preserve.iter().map(|p| p.to_string()).collect(), hardlink: false }
+    }
+
+    #[test]
+    fn a_mirror_sync_deletes_stale_paths_but_not_preserved_ones() {
+        let (release, working) = dirs("prune", &["app", "static/a.css"], &["app", "static/old.css", "data/db", "uploads/x/y", "old/x"]);
+        let (release_dir, working_dir) = (release.to_str().unwrap(), working.to_str().unwrap());
+
+        config(SyncMode::Mirror, &["data/", "uploads"]).prune(release_dir, working_dir).unwrap();
+        assert!(working.join("app").exists());
+        assert!(working.join("data/db").exists());
+        assert!(working.join("uploads/x/y").exists());
+        assert!(!working.join("static/old.css").exists());
+        assert!(!working.join("old").exists());
+
+        std::fs::remove_dir_all(release.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_copy_sync_deletes_nothing() {
+        let (release, working) = dirs("no-prune", &["app"], &["app", "old/x"]);
+        let (release_dir, working_dir) = (release.to_str().unwrap(), working.to_str().unwrap());
+
+        let copy = config(SyncMode::Copy, &[]);
+        assert_eq!(copy.stale_paths(release_dir, working_dir).unwrap(), vec![working.join("old")]);
+        copy.prune(release_dir, working_dir).unwrap();
+        assert!(working.join("old/x").exists());
+
+        std::fs::remove_dir_all(release.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn linked_releases_share_the_store_and_drop_what_no_slot_uses() {
+        let (release, working) = dirs("link", &["app", "static/a.css"], &[]);
+        let store = release.parent().unwrap().join("store");
+        std::fs::create_dir_all(&store).unwrap();
+
+        let mut first = SyncStats::default();
+        copy_dir(&release, &working, Some(&store), &mut first).unwrap();
+        assert_eq!((first.copied, first.linked, first.unchanged), (2, 2, 0));
+
+        let mut again = SyncStats::default();
+        copy_dir(&release, &working, Some(&store), &mut again).unwrap();
+        assert_eq!((again.copied, again.linked, again.unchanged), (0, 0, 2));
+
+        // a changed file gets a new entry, and the old one goes once nothing links to it
+        File::open(release.join("app")).unwrap().set_modified(std::time::UNIX_EPOCH).unwrap();
+        let mut changed = SyncStats::default();
+        copy_dir(&release, &working, Some(&store), &mut changed).unwrap();
+        assert_eq!((changed.copied, changed.linked, changed.unchanged), (1, 1, 1));
+        remove_unlinked(&store).unwrap();
+
+        let stored: Vec<_> = std::fs::read_dir(&store).unwrap().map(|e| e.unwrap().metadata().unwrap()).collect();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().all(|m| m.nlink() == 2));
+
+        std::fs::remove_dir_all(release.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn copies_keep_read_only_files_mtime() {
+        let (release, working) = dirs("mtime", &["app"], &[]);
+        let from = release.join("app");
+        std::fs::set_permissions(&from, std::fs::Permissions::from_mode(0o444)).unwrap();
+        File::open(&from).unwrap().set_modified(std::time::UNIX_EPOCH + Duration::from_secs(1_000_000)).unwrap();
+        std::fs::create_dir_all(&working).unwrap();
+
+        let metadata = from.metadata().unwrap();
+        let to = working.join("app");
+        copy_file(&from, &to, &metadata, &mut SyncStats::default()).unwrap();
+        assert_eq!(to.metadata().unwrap().modified().unwrap(), metadata.modified().unwrap());
+        assert_eq!(store_key(&from, &metadata), store_key(&from, &from.metadata().unwrap()));
+
+        std::fs::remove_dir_all(release.parent().unwrap()).unwrap();
+    }
+}
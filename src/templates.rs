@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::*;
+
+use crate::App;
+use crate::registration::types::Service;
+use crate::supervisor::builtin;
+use crate::sync::remove_if_exists;
+
+/// Release files ending in this are rendered for each slot, and written without it.
+pub(crate) const TEMPLATE_SUFFIX: &str = ".dorc.tmpl";
+
+/// Renders every template in the release into the slot's working dir.
+/// Returns how many there were.
+pub(crate) fn render_all(app: &App, service: &Service) -> Result<usize> {
+    let mut vars: BTreeMap<String, String> = builtin::environment(app, service)?.into_iter()
+        .map(|(key, value)| (format!("env.{}", key), value))
+        .collect();
+    vars.insert("app".to_string(), app.app_name.clone());
+    vars.insert("color".to_string(), service.color().to_string());
+    vars.insert("port".to_string(), service.port.to_string());
+    vars.insert("instances".to_string(), service.instances.max(1).to_string());
+    vars.insert("working_dir".to_string(), service.working_dir.clone());
+    vars.insert("release_id".to_string(), service.release_id.clone().unwrap_or_default());
+
+    let mut rendered = 0;
+    render_dir(Path::new(&app.release_dir), Path::new(&service.working_dir), &vars, &mut rendered)?;
+    Ok(rendered)
+}
+
+fn render_dir(from: &Path, to: &Path, vars: &BTreeMap<String, String>, rendered: &mut usize) -> Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if entry.file_type()?.is_dir() {
+            render_dir(&entry.path(), &to.join(&name), vars, rendered)?;
+            continue;
+        }
+        let target = match name.strip_suffix(TEMPLATE_SUFFIX) {
+            Some(target) => to.join(target),
+            None => continue,
+        };
+
+        let template = std::fs::read_to_string(entry.path())?;
+        let contents = render(&template, vars).map_err(|e| anyhow!("could not render {:?}: {}", entry.path(), e))?;
+        // `env.*` includes secrets, so only the owner (the service's user, once it's chowned) can read it
+        let mode = 0o600 | (entry.metadata()?.permissions().mode() & 0o100);
+        if std::fs::read_to_string(&target).ok().as_deref() != Some(contents.as_str()) {
+            // the old file may be linked into other slots
+            remove_if_exists(&target)?;
+            std::fs::OpenOptions::new().write(true).create_new(true).mode(mode).open(&target)?
+                .write_all(contents.as_bytes())?;
+        }
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(mode))?;
+        *rendered += 1;
+    }
+
+    Ok(())
+}
+
+/// Fills in `{{ name }}`s, failing on any it doesn't know.
+fn render(template: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let (name, after) = rest[start + 2..].split_once("}}")
+            .ok_or_else(|| anyhow!("unclosed `{{{{` on line {}", template[..template.len() - rest.len() + start].matches('\n').count() + 1))?;
+
+        match vars.get(name.trim()) {
+            Some(value) => rendered.push_str(value),
+            None => bail!("unknown variable `{}`, use app, color, port, instances, working_dir, release_id or env.<NAME>", name.trim()),
+        }
+        rest = after;
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> BTreeMap<String, String> {
+        [("port", "9101"), ("color", "blue"), ("env.TOKEN", "s3cret")].iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn fills_in_variables() {
+        let rendered = render("port = {{port}}\ncolor = \"{{ color }}\"\ntoken = {{ env.TOKEN }}\n", &vars()).unwrap();
+        assert_eq!(rendered, "port = 9101\ncolor = \"blue\"\ntoken = s3cret\n");
+    }
+
+    #[test]
+    fn rejects_unknown_and_unclosed_variables() {
+        assert!(render("{{ colour }}", &vars()).unwrap_err().to_string().contains("unknown variable `colour`"));
+        assert!(render("a\nb = {{ port", &vars()).unwrap_err().to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn rendered_files_are_private() {
+        let root = std::env::temp_dir().join(format!("dorc-templates-{}", std::process::id()));
+        let (from, to) = (root.join("release"), root.join("working"));
+        std::fs::create_dir_all(&from).unwrap();
+        std::fs::create_dir_all(&to).unwrap();
+        for (name, mode) in [("config.toml", 0o644), ("run.sh", 0o755)] {
+            let template = from.join(format!("{}{}", name, TEMPLATE_SUFFIX));
+            std::fs::write(&template, "{{ env.TOKEN }}").unwrap();
+            std::fs::set_permissions(&template, std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+
+        let mut rendered = 0;
+        render_dir(&from, &to, &vars(), &mut rendered).unwrap();
+        let mode = |name: &str| to.join(name).metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(rendered, 2);
+        assert_eq!(mode("config.toml"), 0o600);
+        assert_eq!(mode("run.sh"), 0o700);
+        assert_eq!(std::fs::read_to_string(to.join("config.toml")).unwrap(), "s3cret");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use dialoguer::console::style;
+
+use crate::App;
+use crate::doctor::{check_hardening, fail, failures, fix, pass};
+use crate::registration::SLOTS;
+use crate::settings::settings;
+use crate::supervisor::SupervisorKind;
+
+/// Checks every app, then exits with 1 if anything's wrong, so it can gate a deploy.
+pub(crate) fn validate() {
+    check_everything(false);
+    if failures() > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// What `validate` checks, plus how well each service is sandboxed if `hardening`.
+pub(crate) fn check_everything(hardening: bool) {
+    let paths = match settings().app_paths() {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", settings().apps_dir, e);
+            return;
+        }
+    };
+
+    let mut apps = vec![];
+    // apps with a config that doesn't load, whose files aren't left over, just unaccounted for
+    let mut unloaded = BTreeSet::new();
+    for path in paths {
+        let app = match App::load(&path) {
+            Ok(app) => app,
+            Err(e) => {
+                println!("{}", style(path.display()).yellow().bold());
+                fail(format!("could not load app: {}", e));
+                println!();
+                unloaded.extend(path.file_stem().map(|name| name.to_string_lossy().into_owned()));
+                continue;
+            }
+        };
+
+        println!("{}", style(&app.app_name).yellow().bold());
+        check_paths(&app);
+        if app.supervisor == SupervisorKind::Systemd {
+            check_units(&app);
+        }
+        if hardening {
+            for service in &app.instances() {
+                check_hardening(&app, service);
+            }
+        }
+        println!();
+        apps.push(app);
+    }
+
+    println!("{}", style("ports").yellow().bold());
+    check_ports(&apps);
+    println!();
+
+    println!("{}", style("leftovers").yellow().bold());
+    check_orphans(&apps, &unloaded);
+}
+
+fn is_executable(path: &str) -> bool {
+    Path::new(path).metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+fn check_paths(app: &App) {
+    if Path::new(&app.release_dir).is_dir() {
+        pass(format!("release dir {} exists", app.release_dir));
+    } else {
+        fail(format!("release dir {} doesn't exist", app.release_dir));
+        fix(format!("create it, or set `release_dir` in {}", settings().app_path(&app.app_name).display()));
+    }
+
+    if is_executable(&app.release_bin) {
+        pass(format!("release executable {} exists", app.release_bin));
+    } else {
+        fail(format!("release executable {} is missing or can't be run", app.release_bin));
+        fix("upload a release that has it, or set `release_bin`".to_string());
+    }
+
+    for slot in &app.slots {
+        // slots that were never released to have nothing to look at yet
+        if slot.release_id.is_none() {
+            continue;
+        }
+
+        if !Path::new(&slot.working_dir).is_dir() {
+            fail(format!("{}'s working dir {} doesn't exist", slot.color(), slot.working_dir));
+            fix(format!("upload a release to copy to {} again", slot.color()));
+            continue;
+        }
+
+        let bin = app.bin_path(slot);
+        if is_executable(&bin) {
+            pass(format!("{} runs {}", slot.color(), bin));
+        } else {
+            fail(format!("{}'s executable {} is missing or can't be run", slot.color(), bin));
+            fix(format!("upload a release to copy to {} again", slot.color()));
+        }
+    }
+}
+
+/// Lines only in `actual` as `-`, lines only in `expected` as `+`.
+fn print_drift(expected: &str, actual: &str) {
+    for line in actual.lines().filter(|line| !expected.lines().any(|e| e == *line)) {
+        println!("        {}", style(format!("- {}", line)).red());
+    }
+    for line in expected.lines().filter(|line| !actual.lines().any(|a| a == *line)) {
+        println!("        {}", style(format!("+ {}", line)).green());
+    }
+}
+
+fn check_units(app: &App) {
+    for service in &app.instances() {
+        let path = service.unit_path();
+        let expected = service.to_unit_file(app);
+        match std::fs::read_to_string(&path) {
+            Ok(actual) if actual == expected => pass(format!("{} is up to date", path)),
+            Ok(actual) => {
+                fail(format!("{} isn't what dorc would write", path));
+                print_drift(&expected, &actual);
+                fix(format!("`dorc load {}` rewrites it, move any changes you want to keep to the app's config", app.app_name));
+            }
+            Err(e) => {
+                fail(format!("could not read {}: {}", path, e));
+                fix(format!("`dorc load {}` writes it", app.app_name));
+            }
+        }
+    }
+
+    if app.socket_activated {
+        let path = format!("{}/{}", settings().unit_dir, app.socket_unit_name());
+        let expected = app.to_systemd_socket();
+        match std::fs::read_to_string(&path) {
+            Ok(actual) if actual == expected => pass(format!("{} is up to date", path)),
+            Ok(actual) => {
+                fail(format!("{} isn't what dorc would write", path));
+                print_drift(&expected, &actual);
+                fix("apply the `+` lines, then restart the socket and dorc".to_string());
+            }
+            Err(e) => {
+                fail(format!("could not read {}: {}", path, e));
+                fix(format!("set `socket_activated = false`, or register {} again", app.app_name));
+            }
+        }
+    }
+}
+
+/// Something listening on a port, and where if it's an app's listen port.
+struct PortUse {
+    owner: String,
+    address: Option<IpAddr>,
+}
+
+impl PortUse {
+    /// Listeners on different, specific addresses can share a port.
+    fn collides_with(&self, other: &PortUse) -> bool {
+        match (self.address, other.address) {
+            (Some(a), Some(b)) => a == b || a.is_unspecified() || b.is_unspecified(),
+            _ => true,
+        }
+    }
+}
+
+fn check_ports(apps: &[App]) {
+    let mut unpicked = 0;
+    for app in apps {
+        let listen = app.listen_address();
+        let unpicked_slots: Vec<String> = app.slots.iter().filter(|slot| slot.port == 0).map(|slot| format!("slot {}", slot.color())).collect();
+        if listen.port() == 0 || !unpicked_slots.is_empty() {
+            unpicked += 1;
+            let what = match listen.port() {
+                0 => std::iter::once("its listen port".to_string()).chain(unpicked_slots).collect::<Vec<_>>().join(", "),
+                _ => unpicked_slots.join(", "),
+            };
+            fail(format!("{} is waiting for the daemon to pick a port for {}", app.app_name, what));
+            fix(format!("`dorc load {}` has the daemon pick them", app.app_name));
+        }
+    }
+
+    let (shared, used) = shared_ports(apps);
+    for (port, owners) in &shared {
+        fail(format!("port {} is used by {}", port, owners.join(" and ")));
+        fix("give all but one of them another port in their app's config".to_string());
+    }
+
+    if shared.is_empty() && unpicked == 0 {
+        pass(format!("{} ports, none shared", used));
+    }
+}
+
+/// Ports with more than one listener on them, and who by, then how many ports are in use.
+fn shared_ports(apps: &[App]) -> (Vec<(u16, Vec<String>)>, usize) {
+    let mut ports: BTreeMap<u16, Vec<PortUse>> = BTreeMap::new();
+    for app in apps {
+        let listen = app.listen_address();
+        if listen.port() != 0 {
+            ports.entry(listen.port()).or_default().push(PortUse {
+                owner: format!("{}'s listen port", app.app_name),
+                address: Some(listen.ip()),
+            });
+        }
+        for service in app.slots.iter().filter(|slot| slot.port != 0).flat_map(|slot| slot.instances()) {
+            ports.entry(service.port).or_default().push(PortUse {
+                owner: service.qualified_name.clone(),
+                address: None,
+            });
+        }
+    }
+
+    let shared = ports.iter()
+        .filter(|(_, uses)| uses.iter().enumerate().any(|(i, a)| uses[i + 1..].iter().any(|b| a.collides_with(b))))
+        .map(|(port, uses)| (*port, uses.iter().map(|u| u.owner.clone()).collect()))
+        .collect();
+    (shared, ports.len())
+}
+
+/// Files dorc wrote for apps or slots that no longer exist.
+/// Anything belonging to one of the `unloaded` apps is left alone, since what it'd keep can't be told.
+fn check_orphans(apps: &[App], unloaded: &BTreeSet<String>) {
+    let app_names: BTreeSet<&str> = apps.iter().map(|app| app.app_name.as_str()).collect();
+    let mut orphans = 0;
+
+    let units: BTreeSet<String> = apps.iter()
+        .filter(|app| app.supervisor == SupervisorKind::Systemd)
+        .flat_map(|app| app.instances())
+        .map(|service| format!("{}.service", service.qualified_name))
+        .collect();
+    let sockets: BTreeSet<String> = apps.iter()
+        .filter(|app| app.socket_activated)
+        .map(|app| app.socket_unit_name())
+        .collect();
+    let systemctl = if settings().user_mode { "systemctl --user" } else { "systemctl" };
+
+    for path in list_dir(&settings().unit_dir) {
+        let name = file_name(&path);
+        let ours = if let Some(app_name) = name.strip_prefix("dorc-").and_then(|n| n.strip_suffix(".socket")) {
+            !sockets.contains(&name) && !app_name.is_empty() && !unloaded.contains(app_name)
+        } else if let Some(app_name) = slot_unit_app(&name) {
+            // for an app dorc knows, or with a working dir dorc made
+            !units.contains(&name)
+                && !unloaded.contains(app_name)
+                && (app_names.contains(app_name) || unit_in_service_data(&path))
+        } else {
+            false
+        };
+
+        if ours {
+            orphans += 1;
+            fail(format!("{} isn't any app's unit", path));
+            fix(format!("{} disable --now {} && rm {}", systemctl, name, path));
+        }
+    }
+
+    let bins: BTreeSet<String> = apps.iter()
+        .filter(|app| app.bin_outside_release())
+        .flat_map(|app| app.slots.iter().map(|slot| slot.slot_name().to_string()))
+        .collect();
+    for path in list_dir(&settings().bin_dir()) {
+        let name = file_name(&path);
+        let app_name = name.split_once('-').map(|(_, app_name)| app_name).unwrap_or_default();
+        if !bins.contains(&name) && !unloaded.contains(app_name) {
+            orphans += 1;
+            fail(format!("{} isn't any slot's binary", path));
+            fix(format!("rm {}", path));
+        }
+    }
+
+    let stores: BTreeSet<&str> = apps.iter()
+        .filter(|app| app.sync.hardlink)
+        .map(|app| app.app_name.as_str())
+        .collect();
+    for path in list_dir(&settings().store_dir()) {
+        let name = file_name(&path);
+        if !stores.contains(name.as_str()) && !unloaded.contains(&name) {
+            orphans += 1;
+            fail(format!("{} is the release store of an app that doesn't use one", path));
+            fix(format!("rm -r {}", path));
+        }
+    }
+
+    if orphans == 0 {
+        pass("nothing left behind by removed apps or slots".to_string());
+    }
+}
+
+/// The app a unit named like a slot's, e.g. `blue-app.service` or `blue-app@9101.service`, would belong to.
+fn slot_unit_app(name: &str) -> Option<&str> {
+    let (color, rest) = name.strip_suffix(".service")?.split_once('-')?;
+    let app_name = rest.split('@').next().unwrap_or(rest);
+    SLOTS.iter().any(|(slot, _)| *slot == color).then_some(app_name)
+}
+
+fn list_dir(dir: &str) -> Vec<String> {
+    let mut paths: Vec<String> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path().to_string_lossy().into_owned()).collect(),
+        Err(_) => vec![],
+    };
+    paths.sort();
+    paths
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Whether a unit runs in a working dir `dorc register` would have suggested.
+fn unit_in_service_data(path: &str) -> bool {
+    std::fs::read_to_string(path).is_ok_and(|unit| {
+        unit.lines()
+            .filter_map(|line| line.trim().strip_prefix("WorkingDirectory="))
+            .any(|dir| Path::new(dir).starts_with(&settings().service_data_dir))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(name: &str, listen: &str, ports: &[u16]) -> App {
+        let slots: String = ports.iter().zip(SLOTS).map(|(port, (color, _))| format!(
+            "[[slots]]\nqualified_name = \"{color}-{name}\"\nworking_dir = \"/srv/{color}-{name}\"\nport = {}\non_start = \"app\"\n",
+            port, color = color, name = name,
+        )).collect();
+        toml::from_str(&format!(
+            "app_name = \"{}\"\nrelease_dir = \"/srv/app\"\nrelease_bin = \"app\"\nactive = \"blue\"\n{}\n{}",
+            name, listen, slots
+        )).unwrap()
+    }
+
+    #[test]
+    fn finds_ports_used_twice() {
+        let apps = [
+            app("one", "listen_port = 8000", &[9000, 9001]),
+            app("two", "listen_port = 9001", &[9002, 9002]),
+        ];
+        let (shared, used) = shared_ports(&apps);
+        assert_eq!(shared, vec![
+            (9001, vec!["green-one".to_string(), "two's listen port".to_string()]),
+            (9002, vec!["blue-two".to_string(), "green-two".to_string()]),
+        ]);
+        assert_eq!(used, 4);
+    }
+
+    #[test]
+    fn listen_ports_on_different_addresses_dont_collide() {
+        let on = |name, address: &str| app(name, &format!("listen_port = 8000\nlisten_address = \"{}\"", address), &[]);
+        assert!(shared_ports(&[on("one", "127.0.0.1"), on("two", "10.0.0.1")]).0.is_empty());
+        assert_eq!(shared_ports(&[on("one", "127.0.0.1"), on("two", "0.0.0.0")]).0.len(), 1);
+    }
+
+    #[test]
+    fn ports_left_for_the_daemon_to_pick_arent_shared() {
+        assert_eq!(shared_ports(&[app("one", "listen_port = 0", &[0, 0])]), (vec![], 0));
+    }
+
+    #[test]
+    fn recognises_slot_units() {
+        assert_eq!(slot_unit_app("blue-app.service"), Some("app"));
+        assert_eq!(slot_unit_app("green-my-app@9101.service"), Some("my-app"));
+        assert_eq!(slot_unit_app("purple-app.service"), None);
+        assert_eq!(slot_unit_app("blue-app.socket"), None);
+        assert_eq!(slot_unit_app("sshd.service"), None);
+    }
+}